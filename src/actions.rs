@@ -0,0 +1,73 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+    thread,
+};
+
+lazy_static! {
+    static ref URL_PATTERN: Regex = Regex::new(r#"https?://[^\s<>"'，。、）】]+"#).unwrap();
+}
+
+/// 通过 OSC 52 把文本写入系统剪贴板，ssh 远程和 tmux（需开启 set-clipboard）中同样有效
+pub fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()
+}
+
+/// 找出消息中的所有链接
+pub fn find_urls(text: &str) -> Vec<String> {
+    URL_PATTERN
+        .find_iter(text)
+        .map(|m| m.as_str().to_owned())
+        .collect()
+}
+
+/// 使用系统默认程序打开链接或文件
+pub fn open_external(target: &str) -> io::Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        Command::new("xdg-open")
+    };
+    command
+        .arg(target)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|mut child| {
+            // 回收子进程，避免留下僵尸进程
+            thread::spawn(move || child.wait());
+        })
+}
+
+/// 使用配置的命令打开文件，命令中的 `{}` 替换为文件路径，没有 `{}` 时路径追加在最后
+pub fn open_with(command: Option<&str>, target: &str) -> io::Result<()> {
+    let command = match command {
+        Some(command) if !command.trim().is_empty() => command,
+        _ => return open_external(target),
+    };
+    let mut parts = command.split_whitespace();
+    let program = parts.next().unwrap_or_default();
+    let mut args: Vec<String> = parts.map(|arg| arg.replace("{}", target)).collect();
+    if !command.contains("{}") {
+        args.push(target.to_owned());
+    }
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|mut child| {
+            thread::spawn(move || child.wait());
+        })
+}
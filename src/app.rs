@@ -2,27 +2,41 @@ use chrono::Local;
 use num_enum::TryFromPrimitive;
 use std::{
     borrow::{Borrow, Cow},
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs, mem,
     ops::AddAssign,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::SystemTimeError,
+    sync::{atomic::Ordering, mpsc},
+    thread,
+    time::{Duration, Instant, SystemTimeError},
 };
 
 use chashmap::{CHashMap, ReadGuard};
+use crossterm::event::{MouseButton, MouseEvent, MouseEventKind};
 use json::JsonValue;
 use rand::{
     distributions::{Distribution, Uniform},
     rngs::ThreadRng,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::info;
-use tui::widgets::ListState;
+use tracing::{error, info};
+use tui::{layout::Rect, text::Spans, widgets::ListState};
 
+use crate::actions;
 use crate::client::{
     self,
     my_custom_runtime::{block_on_return, spawn},
-    ActionResult, ClientMethod, ContactMessage, ContactUserInfo, InputMessage, MessageChannel,
-    ResponseChannel, SystemRequest, RESPONSE_WAITING_LIST,
+    ActionResult, ClientMethod, ContactMessage, ContactUserInfo, GroupMember, InputMessage,
+    LoginStatus, MessageChannel, MessageKind, QuoteRef, RecallNotice, ResponseChannel, ServerHello,
+    SystemRequest,
 };
+use crate::config::{self, Config, ConversationLayout, ImageProtocol};
+use crate::emoji;
+use crate::notify;
+use crate::preview;
+use image::RgbImage;
 
 extern crate chrono;
 use chrono::prelude::*;
@@ -149,6 +163,8 @@ pub struct StatefulList<T> {
     pub state: ListState,
     pub items: Vec<T>,
     pub mark: u16,
+    /// 第一行可见条目的下标，与 tui 内部的滚动位置保持一致
+    pub offset: usize,
 }
 
 impl<T> StatefulList<T> {
@@ -157,6 +173,7 @@ impl<T> StatefulList<T> {
             state: ListState::default(),
             items: Vec::new(),
             mark,
+            offset: 0,
         }
     }
     pub fn with_items(mark: u16, items: Vec<T>) -> StatefulList<T> {
@@ -164,6 +181,7 @@ impl<T> StatefulList<T> {
             state: ListState::default(),
             items,
             mark,
+            offset: 0,
         }
     }
 
@@ -200,6 +218,592 @@ impl<T> StatefulList<T> {
         };
         self.state.select(Some(i));
     }
+
+    /// 鼠标滚轮向下，到底后不再回到开头
+    pub fn scroll_down(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+        let i = match self.state.selected() {
+            Some(i) => (i + 1).min(self.items.len() - 1),
+            None => 0,
+        };
+        self.state.select(Some(i));
+    }
+
+    pub fn scroll_up(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+        let i = match self.state.selected() {
+            Some(i) => i.saturating_sub(1),
+            None => 0,
+        };
+        self.state.select(Some(i));
+    }
+
+    /// 按 tui `List` 的规则计算单行条目在给定高度下的滚动位置
+    pub fn sync_offset(&mut self, height: usize) {
+        if self.items.is_empty() || height == 0 {
+            return;
+        }
+        let mut offset = self.offset.min(self.items.len() - 1);
+        let selected = self.state.selected().unwrap_or(0).min(self.items.len() - 1);
+        if selected >= offset + height {
+            offset = selected + 1 - height;
+        }
+        if selected < offset {
+            offset = selected;
+        }
+        self.offset = offset;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum MessageAction {
+    Copy,
+    Reply,
+    Forward,
+    OpenUrl,
+    Recall,
+    ShowLocation,
+    ViewImage,
+    Download,
+    Open,
+    Hide,
+}
+
+impl MessageAction {
+    pub fn label(&self) -> &'static str {
+        match self {
+            MessageAction::Copy => "复制",
+            MessageAction::Reply => "引用回复",
+            MessageAction::Forward => "转发",
+            MessageAction::OpenUrl => "打开链接",
+            MessageAction::Recall => "撤回",
+            MessageAction::ShowLocation => "查看地图",
+            MessageAction::ViewImage => "查看图片",
+            MessageAction::Download => "下载附件",
+            MessageAction::Open => "下载并打开",
+            MessageAction::Hide => "隐藏",
+        }
+    }
+}
+
+pub enum Popup {
+    /// 聊天窗选中消息的操作菜单
+    MessageActions(StatefulList<MessageAction>),
+    /// 转发或群发的目标，可多选
+    Recipients(RecipientPicker),
+    /// 群发进度和每个目标的发送结果
+    Broadcast(ListState),
+    /// 消息中有多个链接时选择要打开的一个
+    OpenUrl(StatefulList<String>),
+    /// 位置消息的地图
+    Location(LocationView),
+    /// 表情选择，条目为表情表中的下标
+    Emoji(StatefulList<usize>),
+    /// 账号切换，条目为账号在 `App::sessions` 中的下标
+    Accounts(StatefulList<usize>),
+    /// 所有账号中有未读消息的会话
+    Unread(StatefulList<UnreadEntry>),
+}
+
+/// 在世界地图上显示位置消息，可以缩放到所在的区域
+pub struct LocationView {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub label: String,
+    /// 缩放级别，0 为整个世界，每放大一级视野缩小一半
+    pub zoom: u8,
+}
+
+impl LocationView {
+    const MAX_ZOOM: u8 = 8;
+
+    /// 以位置为中心的经度、纬度范围，靠近地图边缘时不超出世界范围
+    pub fn bounds(&self) -> ([f64; 2], [f64; 2]) {
+        let scale = (1u32 << self.zoom) as f64;
+        let (half_x, half_y) = (180.0 / scale, 90.0 / scale);
+        let center_x = self.longitude.clamp(-180.0 + half_x, 180.0 - half_x);
+        let center_y = self.latitude.clamp(-90.0 + half_y, 90.0 - half_y);
+        (
+            [center_x - half_x, center_x + half_x],
+            [center_y - half_y, center_y + half_y],
+        )
+    }
+
+    fn zoom_in(&mut self) {
+        self.zoom = (self.zoom + 1).min(Self::MAX_ZOOM);
+    }
+
+    fn zoom_out(&mut self) {
+        self.zoom = self.zoom.saturating_sub(1);
+    }
+}
+
+impl Popup {
+    fn next(&mut self) {
+        match self {
+            Popup::MessageActions(list) => list.next(),
+            Popup::Recipients(picker) => picker.list.next(),
+            Popup::Broadcast(_) => {}
+            Popup::OpenUrl(list) => list.next(),
+            Popup::Location(view) => view.zoom_out(),
+            Popup::Emoji(list) => list.next(),
+            Popup::Accounts(list) => list.next(),
+            Popup::Unread(list) => list.next(),
+        }
+    }
+
+    fn previous(&mut self) {
+        match self {
+            Popup::MessageActions(list) => list.previous(),
+            Popup::Recipients(picker) => picker.list.previous(),
+            Popup::Broadcast(_) => {}
+            Popup::OpenUrl(list) => list.previous(),
+            Popup::Location(view) => view.zoom_in(),
+            Popup::Emoji(list) => list.previous(),
+            Popup::Accounts(list) => list.previous(),
+            Popup::Unread(list) => list.previous(),
+        }
+    }
+}
+
+pub struct RecipientPicker {
+    /// (unique_id, display_name)
+    pub list: StatefulList<(String, String)>,
+    /// 已勾选的条目下标
+    pub chosen: HashSet<usize>,
+    /// 要转发的消息，为空表示选完目标后再编辑一条新消息群发
+    pub message: Option<String>,
+}
+
+impl RecipientPicker {
+    fn new(groups: &[ContactUserInfo], message: Option<String>) -> RecipientPicker {
+        let targets = groups
+            .iter()
+            .map(|c| (c.unique_id.to_string(), c.display_name.to_string()))
+            .collect();
+        let mut list = StatefulList::with_items(0, targets);
+        list.state.select(Some(0));
+        RecipientPicker {
+            list,
+            chosen: HashSet::new(),
+            message,
+        }
+    }
+
+    fn toggle(&mut self) {
+        if let Some(idx) = self.list.state.selected() {
+            if !self.chosen.remove(&idx) {
+                self.chosen.insert(idx);
+            }
+        }
+    }
+
+    /// 勾选的目标，一个都没勾选时取光标所在的那个
+    fn targets(&self) -> Vec<(String, String)> {
+        let mut chosen: Vec<usize> = self.chosen.iter().copied().collect();
+        if chosen.is_empty() {
+            chosen.extend(self.list.state.selected());
+        }
+        chosen.sort_unstable();
+        chosen
+            .into_iter()
+            .filter_map(|idx| self.list.items.get(idx).cloned())
+            .collect()
+    }
+}
+
+pub enum DeliveryState {
+    /// 排队等待发送
+    Queued,
+    /// 已发出，等待服务端响应：traceId 和发出的时间
+    Sent(String, Instant),
+    Delivered,
+    Failed(String),
+}
+
+pub struct Delivery {
+    pub unique_id: String,
+    pub display_name: String,
+    pub state: DeliveryState,
+}
+
+/// 一次转发或群发任务，每个 tick 最多发出一条，两条之间至少间隔 `broadcast_interval`
+pub struct Broadcast {
+    pub text: String,
+    pub deliveries: Vec<Delivery>,
+    last_sent: Option<Instant>,
+}
+
+impl Broadcast {
+    pub fn finished(&self) -> usize {
+        self.deliveries
+            .iter()
+            .filter(|d| matches!(d.state, DeliveryState::Delivered | DeliveryState::Failed(_)))
+            .count()
+    }
+
+    /// 还有消息等待发送或者等待响应
+    fn busy(&self) -> bool {
+        self.deliveries
+            .iter()
+            .any(|d| matches!(d.state, DeliveryState::Queued | DeliveryState::Sent(..)))
+    }
+
+    /// 根据 sendChatMessage 的响应更新对应的消息，返回是否有消息因此有了结果
+    fn on_response(&mut self, trace_id: &str, response: &str) -> bool {
+        let delivery = self
+            .deliveries
+            .iter_mut()
+            .find(|d| matches!(&d.state, DeliveryState::Sent(id, _) if id == trace_id));
+        match delivery {
+            Some(delivery) => {
+                delivery.state = match client::parse_json_for_status(response) {
+                    Some(result) if result.success => DeliveryState::Delivered,
+                    Some(result) => DeliveryState::Failed(result.message.unwrap_or_default()),
+                    None => DeliveryState::Failed(String::from("无法解析响应")),
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 发出后超过 `timeout` 仍没有响应的消息算作失败，返回是否有消息超时
+    fn expire(&mut self, timeout: Duration) -> bool {
+        let mut expired = false;
+        for delivery in self.deliveries.iter_mut() {
+            if matches!(&delivery.state, DeliveryState::Sent(_, at) if at.elapsed() >= timeout) {
+                delivery.state = DeliveryState::Failed(String::from("等待响应超时"));
+                expired = true;
+            }
+        }
+        expired
+    }
+}
+
+/// 最近一次绘制时各区域的位置，用于鼠标点击定位
+#[derive(Default)]
+pub struct PaneAreas {
+    pub groups: Rect,
+    /// 用户列表去掉边框后的区域
+    pub groups_list: Rect,
+    pub conversation: Rect,
+    pub input: Rect,
+}
+
+/// 聊天窗按行滚动的状态
+#[derive(Default)]
+pub struct ConversationScroll {
+    /// 第一行可见行的下标，None 表示停在底部并跟随新消息
+    pub top: Option<usize>,
+    /// 向上翻看期间当前会话收到的新消息数
+    pub unseen: usize,
+    /// 最近一次绘制时的总行数和可见行数
+    pub total_lines: usize,
+    pub height: usize,
+    /// 下次绘制时把选中的消息滚动到可见范围内
+    pub reveal_selected: bool,
+}
+
+impl ConversationScroll {
+    pub fn max_top(&self) -> usize {
+        self.total_lines.saturating_sub(self.height)
+    }
+
+    fn page(&self) -> usize {
+        self.height.saturating_sub(1).max(1)
+    }
+
+    pub fn follow(&mut self) {
+        self.top = None;
+        self.unseen = 0;
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let top = self.top.unwrap_or_else(|| self.max_top());
+        self.top = Some(top.saturating_sub(lines));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        if let Some(top) = self.top {
+            if top + lines >= self.max_top() {
+                self.follow();
+            } else {
+                self.top = Some(top + lines);
+            }
+        }
+    }
+
+    pub fn page_up(&mut self) {
+        self.scroll_up(self.page());
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll_down(self.page());
+    }
+}
+
+/// 输入框中 `@name` 的 Tab 补全状态，连续按 Tab 在候选之间切换
+pub struct Completion {
+    /// `@` 在输入框中的字节下标
+    start: usize,
+    pub candidates: Vec<GroupMember>,
+    pub index: usize,
+}
+
+/// 输入框中 `:shortcode` 的 Tab 补全状态，候选为表情表中的下标
+pub struct EmojiCompletion {
+    /// `:` 在输入框中的字节下标
+    start: usize,
+    pub candidates: Vec<usize>,
+    pub index: usize,
+}
+
+/// 微信用 @昵称 后面跟一个四分之一全角空格（U+2005）来标记提及
+const MENTION_SEPARATOR: char = '\u{2005}';
+
+/// 服务端不支持引用时使用的文本引用格式
+fn quote_as_text(quote: &QuoteRef, msg: &str) -> String {
+    format!(
+        "「{}: {}」\n- - - - - - - - - - - - - - -\n{}",
+        quote.display_name, quote.text, msg
+    )
+}
+
+/// 附件下载完成后要做的事
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DownloadAction {
+    Save,
+    Open,
+    /// 下载到缓存目录用于图片预览，不提示
+    Preview,
+}
+
+/// 等待响应的附件下载
+struct PendingDownload {
+    attachment_id: String,
+    /// 下载过程中写入的临时文件
+    part_path: PathBuf,
+    /// 服务端没有给出文件名时使用的名字
+    file_name: String,
+    action: DownloadAction,
+}
+
+/// 全屏查看的图片
+pub struct ImageViewer {
+    pub attachment_id: String,
+    /// 使用 kitty/sixel 时图片输出的位置，由 ui 在绘制时填写
+    pub area: Option<Rect>,
+    /// 图形协议的数据是否已经输出，终端大小变化后需要重新输出
+    pub drawn: bool,
+}
+
+/// 一个账号的连接、联系人和消息。当前账号的这些数据直接放在 `App` 中，
+/// 其余账号保存在 `App::sessions` 里，切换账号时两边交换
+pub struct Session<'a> {
+    pub account: String,
+    message_callback: MessageChannel,
+    groups: StatefulList<ContactUserInfo<'a>>,
+    tasks: StatefulList<Message>,
+    target_id: Option<Cow<'a, str>>,
+    target_display_name: Option<String>,
+    message_shard: CHashMap<String, Vec<Message>>,
+    message_unread: CHashMap<String, u16>,
+    message_latest_time: CHashMap<String, i64>,
+    message_mentioned: HashSet<String>,
+    scroll: ConversationScroll,
+    pending_quotes: HashMap<String, (String, String, Vec<String>)>,
+    quote_supported: Option<bool>,
+    broadcast: Option<Broadcast>,
+    group_members: HashMap<String, Vec<GroupMember>>,
+    pending_member_requests: HashMap<String, String>,
+    pending_downloads: HashMap<String, PendingDownload>,
+    pending_uploads: HashMap<String, String>,
+    pending_sends: HashMap<String, String>,
+    pending_recalls: HashMap<String, (String, String)>,
+    login: Option<LoginStatus>,
+    login_trace: Option<String>,
+    last_login_check: Instant,
+    server: Option<ServerHello>,
+    hello_trace: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(account: String, message_callback: MessageChannel) -> Session<'a> {
+        Session {
+            account,
+            message_callback,
+            groups: StatefulList::new(AppBlock::GroupList as u16),
+            tasks: StatefulList::new(AppBlock::DialogDetail as u16),
+            target_id: None,
+            target_display_name: None,
+            message_shard: CHashMap::new(),
+            message_unread: CHashMap::new(),
+            message_latest_time: CHashMap::new(),
+            message_mentioned: HashSet::new(),
+            scroll: ConversationScroll::default(),
+            pending_quotes: HashMap::new(),
+            quote_supported: None,
+            broadcast: None,
+            group_members: HashMap::new(),
+            pending_member_requests: HashMap::new(),
+            pending_downloads: HashMap::new(),
+            pending_uploads: HashMap::new(),
+            pending_sends: HashMap::new(),
+            pending_recalls: HashMap::new(),
+            login: None,
+            login_trace: None,
+            last_login_check: Instant::now(),
+            server: None,
+            hello_trace: None,
+        }
+    }
+}
+
+/// 未读汇总中的一个会话
+pub struct UnreadEntry {
+    /// 账号在 `App::sessions` 中的下标
+    pub account: usize,
+    pub account_name: String,
+    pub unique_id: String,
+    pub display_name: String,
+    pub count: u16,
+}
+
+/// 未读总数，不计免打扰的会话
+fn unread_total(
+    groups: &[ContactUserInfo],
+    unread: &CHashMap<String, u16>,
+    muted: &HashSet<String>,
+) -> u32 {
+    groups
+        .iter()
+        .filter(|c| !muted.contains(c.unique_id.as_ref()))
+        .filter_map(|c| unread.get(c.unique_id.as_ref()))
+        .map(|n| *n as u32)
+        .sum()
+}
+
+/// 有未读消息的会话
+fn unread_entries(
+    account: usize,
+    account_name: &str,
+    groups: &[ContactUserInfo],
+    unread: &CHashMap<String, u16>,
+) -> Vec<UnreadEntry> {
+    groups
+        .iter()
+        .filter_map(|c| {
+            let count = unread.get(c.unique_id.as_ref()).map(|n| *n).unwrap_or(0);
+            (count > 0).then(|| UnreadEntry {
+                account,
+                account_name: account_name.to_owned(),
+                unique_id: c.unique_id.to_string(),
+                display_name: c.display_name.to_string(),
+                count,
+            })
+        })
+        .collect()
+}
+
+/// 群发的消息发出后等待响应的最长时间，超时算作失败，避免一直占着群发
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 未登录时查询登录状态的间隔
+const LOGIN_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// 自己发送的消息多久之内可以撤回，与微信一致为两分钟
+const RECALL_WINDOW_MS: i64 = 2 * 60 * 1000;
+
+/// 每次最多为多少张图片准备预览
+const PREVIEW_BATCH: usize = 20;
+
+/// 同时下载的预览图片数量。请求连接一次只处理一个请求，预览下载排得太多会拖慢发出的消息
+const PREVIEW_DOWNLOADS: usize = 1;
+
+/// 图片预览的缓存目录
+fn preview_cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("fishloaf")
+        .join("images")
+}
+
+/// 附件 id 用作文件名时只保留安全的字符
+fn safe_file_name(attachment_id: &str) -> String {
+    attachment_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// 图片是否已经下载到预览缓存中
+fn preview_cached(message: &Message) -> bool {
+    match &message.attachment_id {
+        Some(id) => preview_cache_dir().join(safe_file_name(id)).exists(),
+        None => false,
+    }
+}
+
+/// 服务端或消息中给出的文件名只取最后一段，空名、`.` 和 `..` 不能使用，避免写到下载目录之外
+fn download_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name.to_owned()),
+    }
+}
+
+/// 附件的默认文件名：文件消息使用原文件名，其他按类型补上扩展名
+fn attachment_file_name(kind: &MessageKind, attachment_id: &str) -> String {
+    let id = safe_file_name(attachment_id);
+    match kind {
+        MessageKind::File { name, .. } => download_file_name(name).unwrap_or(id),
+        MessageKind::Image { .. } => format!("{}.jpg", id),
+        MessageKind::Voice { .. } => format!("{}.amr", id),
+        MessageKind::Video { .. } => format!("{}.mp4", id),
+        _ => id,
+    }
+}
+
+/// 下载目录中已有同名文件时在文件名后加上序号，文件名不在下载目录中时返回 `None`
+fn unique_path(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    if Path::new(file_name).file_name() != Some(OsStr::new(file_name)) {
+        return None;
+    }
+    let path = dir.join(file_name);
+    if path.parent() != Some(dir) {
+        return None;
+    }
+    if !path.exists() {
+        return Some(path);
+    }
+    let name = Path::new(file_name);
+    let stem = name
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = name
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+}
+
+fn contains(area: Rect, column: u16, row: u16) -> bool {
+    column >= area.x && column < area.x + area.width && row >= area.y && row < area.y + area.height
 }
 
 pub struct Signal<S: Iterator> {
@@ -247,6 +851,45 @@ pub struct Server<'a> {
 pub struct Message {
     pub message: String,
     pub speaker: String,
+    /// 发言人的 id，用于合并连续消息和计算昵称颜色
+    pub speaker_id: String,
+    /// 是否是自己发送的消息
+    pub mine: bool,
+    /// 服务端的消息 id，本地回显的消息没有
+    pub msg_id: Option<String>,
+    /// 引用回复中被引用的消息
+    pub quote: Option<QuoteRef>,
+    pub kind: MessageKind,
+    /// 是否命中关键词或提及规则
+    pub highlight: bool,
+    /// 图片、文件等附件的 id
+    pub attachment_id: Option<String>,
+    /// 已被撤回，开启防撤回时保留原文
+    pub recalled: bool,
+    /// 本地回显的消息对应的发送请求，收到响应后用来补上服务端的消息 id
+    pub trace_id: Option<String>,
+    /// 收到或发送的时间（毫秒）
+    pub timestamp: i64,
+}
+
+impl Message {
+    /// 聊天窗中显示的内容：非文本消息在正文前加上类型标签
+    pub fn display_text(&self) -> String {
+        match self.kind.label() {
+            Some(label) if self.message.is_empty() => label,
+            Some(label) => format!("{} {}", label, self.message),
+            None => self.message.clone(),
+        }
+    }
+
+    /// 消息中的链接，包括链接消息本身的地址
+    pub fn urls(&self) -> Vec<String> {
+        let mut urls = actions::find_urls(&self.message);
+        if let MessageKind::Link { url, .. } = &self.kind {
+            urls.insert(0, url.clone());
+        }
+        urls
+    }
 }
 
 pub struct App<'a> {
@@ -265,10 +908,84 @@ pub struct App<'a> {
     pub message_callback: MessageChannel,
     pub focus: u16,
     pub target_id: Option<Cow<'a, str>>,
-    pub target_display_name: Option<Box<String>>,
+    pub target_display_name: Option<String>,
     pub message_shard: CHashMap<String, Vec<Message>>,
     pub message_unread: CHashMap<String, u16>,
     pub message_latest_time: CHashMap<String, i64>,
+    /// 有未读的高亮消息（关键词或提及）的会话
+    pub message_mentioned: HashSet<String>,
+    pub config: Config,
+    /// 最近一次键盘操作的时间，用于判断是否需要自动锁屏
+    pub last_active: Instant,
+    pub locked: bool,
+    pub lock_input: String,
+    pub lock_error: bool,
+    /// 锁屏期间收到的消息数
+    pub locked_received: u32,
+    /// 上次写入终端标题的未读总数
+    title_unread: Option<u32>,
+    pub areas: PaneAreas,
+    pub scroll: ConversationScroll,
+    /// 当前弹出的菜单或选择框，打开时接管方向键、回车和 ESC
+    pub popup: Option<Popup>,
+    /// 正在引用回复的消息
+    pub reply_to: Option<Message>,
+    pub status: Option<(String, Instant)>,
+    /// 带引用发送、还在等待响应的消息：traceId -> (target_id, 文本引用形式的正文, 提及的成员)
+    pending_quotes: HashMap<String, (String, String, Vec<String>)>,
+    /// 服务端是否支持引用回复，收到第一条带引用消息的响应之前未知
+    quote_supported: Option<bool>,
+    /// 编辑群发消息时已经选好的目标
+    pub broadcast_targets: Option<Vec<(String, String)>>,
+    pub broadcast: Option<Broadcast>,
+    /// 群成员缓存，打开群会话时请求
+    pub group_members: HashMap<String, Vec<GroupMember>>,
+    /// 等待响应的群成员请求：traceId -> 群 id
+    pending_member_requests: HashMap<String, String>,
+    /// 输入框中通过补全插入的 @ 成员，发送时随消息一起带上
+    pending_mentions: Vec<GroupMember>,
+    pub completion: Option<Completion>,
+    pub emoji_completion: Option<EmojiCompletion>,
+    /// 是否用 Unicode 表情显示微信表情代码
+    pub enhanced_graphics: bool,
+    /// 正在下载的附件：traceId -> 下载信息
+    pending_downloads: HashMap<String, PendingDownload>,
+    /// 正在发送的文件：traceId -> 文件名
+    pending_uploads: HashMap<String, String>,
+    /// 等待响应的发送请求：traceId -> 会话 id
+    pending_sends: HashMap<String, String>,
+    /// 等待响应的撤回请求：traceId -> (会话 id, 消息 id)
+    pending_recalls: HashMap<String, (String, String)>,
+    /// 已经解码的图片：附件 id -> 图片
+    pub previews: HashMap<String, RgbImage>,
+    /// 聊天窗中渲染好的图片：(附件 id, 最大列数, 最大行数) -> 半块字符行，窗口大小变化时清空
+    pub preview_lines: HashMap<(String, u16, u16), Vec<Spans<'static>>>,
+    /// 已经请求过预览的附件，失败后也不再重试
+    preview_requested: HashSet<String>,
+    preview_sender: mpsc::Sender<(String, Result<RgbImage, String>)>,
+    preview_receiver: mpsc::Receiver<(String, Result<RgbImage, String>)>,
+    pub viewer: Option<ImageViewer>,
+    /// 全屏查看图片使用的图形协议
+    pub image_protocol: ImageProtocol,
+    /// 下一次绘制前需要清屏重绘
+    pub redraw: bool,
+    /// 服务端最近一次告知的登录状态
+    pub login: Option<LoginStatus>,
+    /// 是否显示登录二维码或账号信息弹窗
+    pub login_modal: bool,
+    login_trace: Option<String>,
+    last_login_check: Instant,
+    /// 握手时服务端告知的协议版本和能力，收到回应之前为空
+    pub server: Option<ServerHello>,
+    hello_trace: Option<String>,
+    /// 当前账号的名称
+    pub account: String,
+    /// 所有账号，当前账号的位置为 None，它的数据就是 App 自身的字段
+    pub sessions: Vec<Option<Session<'a>>>,
+    /// 当前账号在 `sessions` 中的下标
+    pub active_session: usize,
+    /// 正在处理后台账号收到的消息和响应
+    in_background: bool,
 }
 
 impl<'a> App<'a> {
@@ -280,6 +997,10 @@ impl<'a> App<'a> {
         if let Some(mut guard) = self.message_unread.get_mut(contact.unique_id.as_ref()) {
             guard.add_assign(1);
         }
+        if self.in_background {
+            // 后台账号打开的会话也要计数，切换回去时再清零
+            return;
+        }
         if let Some(idx) = &self.target_id {
             self.message_unread.insert(idx.as_ref().to_owned(), 0u16);
         }
@@ -292,7 +1013,7 @@ impl<'a> App<'a> {
             .insert(contact.unique_id.as_ref().to_owned(), timestamp);
     }
 
-    fn message_shard(&mut self, contact: &ContactMessage) {
+    fn message_shard(&mut self, contact: &ContactMessage, message: Message) {
         if !self.message_shard.contains_key(contact.unique_id.as_ref()) {
             self.message_shard.insert_new(
                 contact.unique_id.as_ref().to_string(),
@@ -300,16 +1021,44 @@ impl<'a> App<'a> {
             );
         }
         if let Some(mut guard) = self.message_shard.get_mut(contact.unique_id.as_ref()) {
-            if contact.echo {
-                guard.push(Message {
-                    message: contact.text.to_string(),
-                    speaker: contact.display_name.to_string() + "(*我)",
-                });
-            } else {
-                guard.push(Message {
-                    message: contact.text.to_string(),
-                    speaker: contact.display_name.to_string(),
-                });
+            guard.push(message);
+        }
+    }
+
+    fn build_message(&self, contact: &ContactMessage) -> Message {
+        let speaker_id = match &contact.speaker_id {
+            Some(id) => id.to_string(),
+            None => contact.display_name.to_string(),
+        };
+        if contact.echo {
+            Message {
+                message: contact.text.to_string(),
+                speaker: contact.display_name.to_string() + "(*我)",
+                speaker_id,
+                mine: true,
+                msg_id: contact.msg_id.as_ref().map(|id| id.to_string()),
+                quote: contact.quote.clone(),
+                kind: contact.kind.clone(),
+                highlight: false,
+                attachment_id: contact.attachment_id.as_ref().map(|id| id.to_string()),
+                recalled: false,
+                trace_id: None,
+                timestamp: Local::now().timestamp_millis(),
+            }
+        } else {
+            Message {
+                message: contact.text.to_string(),
+                speaker: contact.display_name.to_string(),
+                speaker_id,
+                mine: false,
+                msg_id: contact.msg_id.as_ref().map(|id| id.to_string()),
+                quote: contact.quote.clone(),
+                kind: contact.kind.clone(),
+                highlight: contact.at_me || self.config.highlight.matches(&contact.text),
+                attachment_id: contact.attachment_id.as_ref().map(|id| id.to_string()),
+                recalled: false,
+                trace_id: None,
+                timestamp: Local::now().timestamp_millis(),
             }
         }
     }
@@ -318,38 +1067,48 @@ impl<'a> App<'a> {
         let receiver: &mut Receiver<String> = &mut self.message_callback.push_notification_receiver;
         if let Ok(message) = receiver.try_recv() {
             if let Ok(jvalue) = json::parse(message.trim()) {
-                if let Ok(contact) = ContactMessage::try_from(jvalue) {
+                if let Ok(notice) = RecallNotice::try_from(&jvalue) {
+                    info!("recall notice: {}", message);
+                    let name = if notice.echo {
+                        String::from("你")
+                    } else {
+                        notice.display_name
+                    };
+                    self.mark_recalled(&notice.unique_id, &notice.msg_id, &name, notice.echo);
+                } else if jvalue["msgType"].as_str() == Some("login") {
+                    match LoginStatus::try_from(&jvalue) {
+                        Ok(status) => self.apply_login_status(status),
+                        Err(_) => error!("parse login status error: {}", message),
+                    }
+                } else if let Ok(contact) = ContactMessage::try_from(jvalue) {
                     info!("parse message success: {}", message.clone());
+                    if self.locked && !contact.echo {
+                        self.locked_received += 1;
+                    }
+                    let msg = self.build_message(&contact);
+                    if msg.highlight {
+                        self.message_mentioned
+                            .insert(contact.unique_id.as_ref().to_owned());
+                    }
+                    self.notify_new_message(&contact, msg.highlight);
                     self.message_unread_count_up(&contact);
-                    self.message_shard(&contact);
-                    self.message_latest_update(&contact);
-                    self.groups.items.sort_by(|a, b| {
-                        let left =
-                            if let Some(num) = self.message_latest_time.get(a.unique_id.as_ref()) {
-                                num.to_owned()
-                            } else {
-                                0i64
-                            };
-                        let right =
-                            if let Some(num) = self.message_latest_time.get(b.unique_id.as_ref()) {
-                                num.to_owned()
-                            } else {
-                                0i64
-                            };
-                        right.cmp(&left)
-                    });
+                    self.message_shard(&contact, msg.clone());
+                    if !self.config.muted.contains(contact.unique_id.as_ref()) {
+                        self.message_latest_update(&contact);
+                    }
+                    self.sort_groups();
                     if let Some(unique) = &self.target_id {
                         if unique.eq(contact.unique_id.as_ref()) {
-                            if contact.echo {
-                                self.tasks.items.push(Message {
-                                    message: contact.text.to_string(),
-                                    speaker: contact.display_name.to_string() + "(*我)",
-                                });
-                            } else {
-                                self.tasks.items.push(Message {
-                                    message: contact.text.to_string(),
-                                    speaker: contact.display_name.to_string(),
-                                });
+                            if !self.in_background {
+                                self.message_mentioned.remove(contact.unique_id.as_ref());
+                            }
+                            let image = matches!(msg.kind, MessageKind::Image { .. });
+                            self.tasks.items.push(msg);
+                            if self.scroll.top.is_some() {
+                                self.scroll.unseen += 1;
+                            }
+                            if image && self.config.images.inline {
+                                self.request_previews();
                             }
                         }
                     }
@@ -360,83 +1119,663 @@ impl<'a> App<'a> {
         }
     }
 
-    pub fn refresh_contact_list(&self) {
-        client::list_user_and_group(
-            &self.message_callback,
-            // Box::new(TestResponseChannel {
-            //     app: &mut self.groups,
-            // }),
-        )
+    /// 置顶的会话排在最前，其余按最近消息时间倒序
+    fn sort_groups(&mut self) {
+        let pinned = &self.config.pinned;
+        let latest_time = &self.message_latest_time;
+        let selected = self
+            .groups
+            .state
+            .selected()
+            .and_then(|idx| self.groups.items.get(idx))
+            .map(|c| c.unique_id.to_string());
+        self.groups.items.sort_by_cached_key(|c| {
+            let time = latest_time
+                .get(c.unique_id.as_ref())
+                .map(|t| *t)
+                .unwrap_or(0i64);
+            (
+                !pinned.contains(c.unique_id.as_ref()),
+                std::cmp::Reverse(time),
+            )
+        });
+        if let Some(unique_id) = selected {
+            let idx = self
+                .groups
+                .items
+                .iter()
+                .position(|c| c.unique_id.eq(unique_id.as_str()));
+            self.groups.state.select(idx);
+        }
     }
 
-    fn dispatch_event(&mut self) {
-        let old_map = RESPONSE_WAITING_LIST.load().clear();
-        if old_map.len() == 0 {
+    /// 命中高亮规则的消息忽略免打扰和会话通知开关，但仍然遵守全局勿扰
+    fn notify_new_message(&self, contact: &ContactMessage, highlight: bool) {
+        if contact.echo
+            || matches!(contact.kind, MessageKind::System)
+            || self.config.dnd.active()
+            || (!highlight && self.config.muted.contains(contact.unique_id.as_ref()))
+        {
             return;
         }
-        old_map.into_iter().for_each(|(_, value)| {
-            if let Some(result) = client::parse_json(value.clone()) {
-                if result.success {
-                    if let Ok(method_enum) = TryInto::<ClientMethod>::try_into(result.method) {
-                        match method_enum {
-                            ClientMethod::listUserAndGroup => {
-                                self.groups.items.truncate(0);
-                                self.groups.items.append(&mut result.data.unwrap());
-                            }
-                            ClientMethod::sendChatMessage => {}
-                        }
-                    }
+        if !self.locked && !self.in_background {
+            if let Some(unique) = &self.target_id {
+                if unique.eq(contact.unique_id.as_ref()) {
+                    return;
                 }
             }
-        });
-    }
-
-    pub fn new(title: &str, enhanced_graphics: bool, call_back: MessageChannel) -> App<'a> {
-        App {
-            title: String::from(title),
-            should_quit: false,
-            message_callback: call_back,
-            tasks: StatefulList::new(AppBlock::DialogDetail as u16),
-            input: String::new(),
-            input_mode: InputMode::Normal,
-            messages: Vec::new(),
-            groups: StatefulList::new(AppBlock::GroupList as u16),
-            focus: 0,
-            target_id: None,
-            message_shard: CHashMap::new(),
-            message_unread: CHashMap::new(),
-            message_latest_time: CHashMap::new(),
-            target_display_name: None,
+        }
+        let conversation = self
+            .groups
+            .items
+            .iter()
+            .find(|c| c.unique_id.eq(&contact.unique_id));
+        let is_group = conversation.map(|c| c.is_group).unwrap_or(false);
+        if !highlight
+            && !self
+                .config
+                .notify
+                .enabled_for(contact.unique_id.as_ref(), is_group)
+        {
+            return;
+        }
+        let mut title = match conversation {
+            Some(c) => c.display_name.to_string(),
+            None => contact.display_name.to_string(),
+        };
+        if self.sessions.len() > 1 {
+            title = format!("[{}] {}", self.account, title);
+        }
+        if self.config.notify.preview && !self.locked {
+            notify::notify_message(
+                &self.config.notify,
+                &title,
+                &format!("{}: {}", contact.display_name, contact.text),
+            );
+        } else {
+            notify::notify_message(&self.config.notify, "fishloaf", "new message");
         }
     }
 
-    pub fn on_up(&mut self) {
-        match self.input_mode {
-            InputMode::Editing => {}
-            InputMode::Normal => {
-                if self.focus == self.tasks.mark {
-                    self.tasks.previous();
-                } else if self.focus == self.groups.mark {
-                    self.groups.previous();
-                }
-            }
+    fn update_title(&mut self) {
+        if !self.config.notify.title {
+            return;
+        }
+        let unread = self
+            .account_summaries()
+            .iter()
+            .map(|(_, unread)| unread)
+            .sum();
+        if self.title_unread != Some(unread) {
+            notify::update_title(unread);
+            self.title_unread = Some(unread);
         }
     }
 
-    pub fn on_down(&mut self) {
-        match self.input_mode {
-            InputMode::Editing => {}
-            InputMode::Normal => {
-                if self.focus == self.tasks.mark {
-                    self.tasks.next();
-                } else if self.focus == self.groups.mark {
-                    self.groups.next();
-                }
+    /// 切换当前选中会话的通知开关，并写回配置文件
+    fn toggle_conversation_notify(&mut self) {
+        if self.focus != self.groups.mark {
+            return;
+        }
+        if let Some(idx) = self.groups.state.selected() {
+            let contact = &self.groups.items[idx];
+            let enabled = self
+                .config
+                .notify
+                .enabled_for(contact.unique_id.as_ref(), contact.is_group);
+            self.config
+                .notify
+                .conversations
+                .insert(contact.unique_id.to_string(), !enabled);
+            if let Err(e) = self.config.save() {
+                error!("save config error: {}", e);
             }
         }
     }
 
-    pub fn on_right(&mut self) {
+    fn toggle_conversation_flag(&mut self, pin: bool) {
+        if self.focus != self.groups.mark {
+            return;
+        }
+        if let Some(idx) = self.groups.state.selected() {
+            let unique_id = self.groups.items[idx].unique_id.to_string();
+            let set = if pin {
+                &mut self.config.pinned
+            } else {
+                &mut self.config.muted
+            };
+            config::toggle_id(set, &unique_id);
+            if pin {
+                self.sort_groups();
+            }
+            if let Err(e) = self.config.save() {
+                error!("save config error: {}", e);
+            }
+        }
+    }
+
+    fn toggle_layout(&mut self) {
+        self.config.layout = match self.config.layout {
+            ConversationLayout::Line => ConversationLayout::Bubble,
+            ConversationLayout::Bubble => ConversationLayout::Line,
+        };
+        if let Err(e) = self.config.save() {
+            error!("save config error: {}", e);
+        }
+    }
+
+    fn toggle_dnd(&mut self) {
+        self.config.dnd.enabled = !self.config.dnd.enabled;
+        if let Err(e) = self.config.save() {
+            error!("save config error: {}", e);
+        }
+    }
+
+    pub fn refresh_contact_list(&self) {
+        client::list_user_and_group(
+            &self.message_callback,
+            // Box::new(TestResponseChannel {
+            //     app: &mut self.groups,
+            // }),
+        )
+    }
+
+    fn say_hello(&mut self) {
+        self.hello_trace = Some(client::hello(&self.message_callback));
+    }
+
+    fn on_hello_result(&mut self, trace_id: &str, response: &str) {
+        if self.hello_trace.as_deref() != Some(trace_id) {
+            return;
+        }
+        self.hello_trace = None;
+        let hello = match client::parse_json_for_hello(response) {
+            Some(ActionResult {
+                data: Some(hello), ..
+            }) => {
+                info!(
+                    "server protocol v{} {:?} capabilities {:?}",
+                    hello.protocol_version, hello.server_version, hello.capabilities
+                );
+                if hello.protocol_version != client::PROTOCOL_VERSION {
+                    self.set_status(format!(
+                        "协议版本不一致：服务端 v{}，客户端 v{}，部分功能可能无法使用",
+                        hello.protocol_version,
+                        client::PROTOCOL_VERSION
+                    ));
+                }
+                hello
+            }
+            _ => {
+                // 旧版本服务端不认识 hello，功能与握手之前一样
+                error!("hello error: {}", response);
+                self.set_status(String::from("服务端不支持协议握手，按旧版本服务端处理"));
+                ServerHello::legacy()
+            }
+        };
+        let login = hello.capabilities.contains(client::CAP_LOGIN);
+        self.message_callback.state.heartbeat.store(
+            hello.capabilities.contains(client::CAP_HEARTBEAT),
+            Ordering::Relaxed,
+        );
+        self.server = Some(hello);
+        if login {
+            self.check_login();
+        }
+    }
+
+    /// 连接断开重连之后重新握手并刷新用户列表，期间推送的消息可能已经丢失
+    fn check_reconnected(&mut self) {
+        if self
+            .message_callback
+            .state
+            .reconnected
+            .swap(false, Ordering::Relaxed)
+        {
+            self.set_status(format!("账号 {} 已重新连接", self.account));
+            self.say_hello();
+            self.refresh_contact_list();
+        }
+    }
+
+    /// 服务端是否支持某项能力，握手完成之前先当作支持
+    fn supports(&self, capability: &str) -> bool {
+        match &self.server {
+            Some(server) => server.capabilities.contains(capability),
+            None => true,
+        }
+    }
+
+    /// 向服务端查询登录状态
+    pub fn check_login(&mut self) {
+        self.login_trace = Some(client::get_login_status(&self.message_callback));
+        self.last_login_check = Instant::now();
+    }
+
+    fn on_login_result(&mut self, trace_id: &str, response: &str) {
+        if self.login_trace.as_deref() != Some(trace_id) {
+            return;
+        }
+        self.login_trace = None;
+        match client::parse_json_for_login(response) {
+            Some(ActionResult {
+                data: Some(status), ..
+            }) => self.apply_login_status(status),
+            _ => error!("get login status error: {}", response),
+        }
+    }
+
+    /// 登录状态变化：掉线时弹出二维码，扫码登录后刷新用户列表并显示账号信息
+    fn apply_login_status(&mut self, status: LoginStatus) {
+        let was = self.login.as_ref().map(|login| login.logged_in);
+        if status.logged_in && was == Some(false) {
+            if let Some(account) = &status.account {
+                self.set_status(format!("已登录为 {}", account.display_name));
+            }
+            self.refresh_contact_list();
+        }
+        if !status.logged_in && was != Some(false) {
+            if self.in_background {
+                self.set_status(String::from("账号未登录"));
+            } else {
+                self.login_modal = true;
+            }
+        }
+        self.login = Some(status);
+    }
+
+    /// 显示账号信息时任意键关闭；二维码还在等待扫码时只响应 ESC，避免误触关掉
+    fn dismiss_login_modal(&mut self) {
+        if !matches!(&self.login, Some(login) if !login.logged_in) {
+            self.login_modal = false;
+        }
+    }
+
+    /// 未登录时定期查询，二维码过期后服务端会给出新的二维码
+    fn poll_login(&mut self) {
+        let logged_out = matches!(&self.login, Some(login) if !login.logged_in);
+        if logged_out && self.last_login_check.elapsed() >= LOGIN_POLL_INTERVAL {
+            self.check_login();
+        }
+    }
+
+    fn dispatch_event(&mut self) {
+        let old_map = self.message_callback.responses.clear();
+        if old_map.len() == 0 {
+            return;
+        }
+        old_map.into_iter().for_each(|(trace_id, value)| {
+            self.on_quote_result(&trace_id, &value);
+            self.on_broadcast_result(&trace_id, &value);
+            self.on_download_result(&trace_id, &value);
+            self.on_upload_result(&trace_id, &value);
+            self.on_send_result(&trace_id, &value);
+            self.on_recall_result(&trace_id, &value);
+            self.on_login_result(&trace_id, &value);
+            self.on_hello_result(&trace_id, &value);
+            if let Some(group_id) = self.pending_member_requests.remove(&trace_id) {
+                match client::parse_json_for_group_member(&value) {
+                    Some(result) if result.success => {
+                        self.group_members
+                            .insert(group_id, result.data.unwrap_or_default());
+                    }
+                    _ => error!("list group member error: {}", value),
+                }
+            }
+            if let Some(result) = client::parse_json(value.clone()) {
+                if result.success {
+                    if let Ok(method_enum) = TryInto::<ClientMethod>::try_into(result.method) {
+                        match method_enum {
+                            ClientMethod::listUserAndGroup => {
+                                self.groups.items.truncate(0);
+                                self.groups.items.append(&mut result.data.unwrap());
+                                self.sort_groups();
+                            }
+                            ClientMethod::sendChatMessage => {}
+                            ClientMethod::listGroupMember => {}
+                            ClientMethod::downloadAttachment => {}
+                            ClientMethod::sendFile => {}
+                            ClientMethod::recallMessage => {}
+                            ClientMethod::getLoginStatus => {}
+                            ClientMethod::auth => {}
+                            ClientMethod::hello => {}
+                            ClientMethod::heartbeat => {}
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn new(
+        title: &str,
+        enhanced_graphics: bool,
+        call_back: MessageChannel,
+        config: Config,
+    ) -> App<'a> {
+        let (preview_sender, preview_receiver) = mpsc::channel();
+        let image_protocol = preview::detect(config.images.protocol);
+        App {
+            title: String::from(title),
+            should_quit: false,
+            message_callback: call_back,
+            tasks: StatefulList::new(AppBlock::DialogDetail as u16),
+            input: String::new(),
+            input_mode: InputMode::Normal,
+            messages: Vec::new(),
+            groups: StatefulList::new(AppBlock::GroupList as u16),
+            focus: 0,
+            target_id: None,
+            message_shard: CHashMap::new(),
+            message_unread: CHashMap::new(),
+            message_latest_time: CHashMap::new(),
+            message_mentioned: HashSet::new(),
+            target_display_name: None,
+            config,
+            last_active: Instant::now(),
+            locked: false,
+            lock_input: String::new(),
+            lock_error: false,
+            locked_received: 0,
+            title_unread: None,
+            areas: PaneAreas::default(),
+            scroll: ConversationScroll::default(),
+            popup: None,
+            reply_to: None,
+            status: None,
+            pending_quotes: HashMap::new(),
+            quote_supported: None,
+            broadcast_targets: None,
+            broadcast: None,
+            group_members: HashMap::new(),
+            pending_member_requests: HashMap::new(),
+            pending_mentions: Vec::new(),
+            completion: None,
+            emoji_completion: None,
+            enhanced_graphics,
+            pending_downloads: HashMap::new(),
+            pending_uploads: HashMap::new(),
+            pending_sends: HashMap::new(),
+            pending_recalls: HashMap::new(),
+            previews: HashMap::new(),
+            preview_lines: HashMap::new(),
+            preview_requested: HashSet::new(),
+            preview_sender,
+            preview_receiver,
+            viewer: None,
+            image_protocol,
+            redraw: false,
+            login: None,
+            login_modal: false,
+            login_trace: None,
+            last_login_check: Instant::now(),
+            server: None,
+            hello_trace: None,
+            account: String::from("default"),
+            sessions: vec![None],
+            active_session: 0,
+            in_background: false,
+        }
+    }
+
+    /// 添加一个账号，在后台接收它的消息
+    pub fn add_account(&mut self, name: String, channel: MessageChannel) {
+        self.sessions.push(Some(Session::new(name, channel)));
+    }
+
+    /// 交换当前账号和 `session` 的数据
+    fn swap_session(&mut self, session: &mut Session<'a>) {
+        mem::swap(&mut self.account, &mut session.account);
+        mem::swap(&mut self.message_callback, &mut session.message_callback);
+        mem::swap(&mut self.groups, &mut session.groups);
+        mem::swap(&mut self.tasks, &mut session.tasks);
+        mem::swap(&mut self.target_id, &mut session.target_id);
+        mem::swap(
+            &mut self.target_display_name,
+            &mut session.target_display_name,
+        );
+        mem::swap(&mut self.message_shard, &mut session.message_shard);
+        mem::swap(&mut self.message_unread, &mut session.message_unread);
+        mem::swap(
+            &mut self.message_latest_time,
+            &mut session.message_latest_time,
+        );
+        mem::swap(&mut self.message_mentioned, &mut session.message_mentioned);
+        mem::swap(&mut self.scroll, &mut session.scroll);
+        mem::swap(&mut self.pending_quotes, &mut session.pending_quotes);
+        mem::swap(&mut self.quote_supported, &mut session.quote_supported);
+        mem::swap(&mut self.broadcast, &mut session.broadcast);
+        mem::swap(&mut self.group_members, &mut session.group_members);
+        mem::swap(
+            &mut self.pending_member_requests,
+            &mut session.pending_member_requests,
+        );
+        mem::swap(&mut self.pending_downloads, &mut session.pending_downloads);
+        mem::swap(&mut self.pending_uploads, &mut session.pending_uploads);
+        mem::swap(&mut self.pending_sends, &mut session.pending_sends);
+        mem::swap(&mut self.pending_recalls, &mut session.pending_recalls);
+        mem::swap(&mut self.login, &mut session.login);
+        mem::swap(&mut self.login_trace, &mut session.login_trace);
+        mem::swap(&mut self.last_login_check, &mut session.last_login_check);
+        mem::swap(&mut self.server, &mut session.server);
+        mem::swap(&mut self.hello_trace, &mut session.hello_trace);
+    }
+
+    /// 依次把每个后台账号换到前台执行 `f`，执行完再换回去
+    fn with_background_sessions(&mut self, mut f: impl FnMut(&mut Self)) {
+        self.in_background = true;
+        for idx in 0..self.sessions.len() {
+            if let Some(mut session) = self.sessions[idx].take() {
+                self.swap_session(&mut session);
+                f(self);
+                self.swap_session(&mut session);
+                self.sessions[idx] = Some(session);
+            }
+        }
+        self.in_background = false;
+    }
+
+    /// 启动时每个账号先握手，再请求用户列表；登录状态在握手完成后按服务端能力查询
+    pub fn connect_accounts(&mut self) {
+        self.say_hello();
+        self.refresh_contact_list();
+        self.with_background_sessions(|app| {
+            app.say_hello();
+            app.refresh_contact_list();
+        });
+    }
+
+    /// 切换到第 `idx` 个账号，输入框中正在编辑的内容保留
+    pub fn switch_account(&mut self, idx: usize) {
+        if idx == self.active_session {
+            return;
+        }
+        let mut session = match self.sessions.get_mut(idx).and_then(Option::take) {
+            Some(session) => session,
+            None => return,
+        };
+        self.swap_session(&mut session);
+        self.sessions[self.active_session] = Some(session);
+        self.active_session = idx;
+        self.popup = None;
+        self.reply_to = None;
+        self.broadcast_targets = None;
+        self.pending_mentions.clear();
+        self.completion = None;
+        self.emoji_completion = None;
+        if let Some(unique_id) = &self.target_id {
+            self.message_unread.insert(unique_id.to_string(), 0u16);
+            self.message_mentioned.remove(unique_id.as_ref());
+        }
+        self.login_modal = matches!(&self.login, Some(login) if !login.logged_in);
+        self.set_status(format!("已切换到账号 {}", self.account));
+    }
+
+    /// 每个账号的名称和未读总数，顺序与配置中一致
+    pub fn account_summaries(&self) -> Vec<(String, u32)> {
+        let muted = &self.config.muted;
+        self.sessions
+            .iter()
+            .map(|session| match session {
+                Some(s) => (
+                    s.account.clone(),
+                    unread_total(&s.groups.items, &s.message_unread, muted),
+                ),
+                None => (
+                    self.account.clone(),
+                    unread_total(&self.groups.items, &self.message_unread, muted),
+                ),
+            })
+            .collect()
+    }
+
+    fn open_account_switcher(&mut self) {
+        let mut list = StatefulList::with_items(0, (0..self.sessions.len()).collect());
+        list.state.select(Some(self.active_session));
+        self.popup = Some(Popup::Accounts(list));
+    }
+
+    /// 汇总所有账号中有未读消息的会话
+    fn open_unread_overview(&mut self) {
+        let mut entries = Vec::new();
+        for (idx, session) in self.sessions.iter().enumerate() {
+            entries.extend(match session {
+                Some(s) => unread_entries(idx, &s.account, &s.groups.items, &s.message_unread),
+                None => {
+                    unread_entries(idx, &self.account, &self.groups.items, &self.message_unread)
+                }
+            });
+        }
+        if entries.is_empty() {
+            self.set_status(String::from("没有未读消息"));
+            return;
+        }
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.count));
+        let mut list = StatefulList::with_items(0, entries);
+        list.state.select(Some(0));
+        self.popup = Some(Popup::Unread(list));
+    }
+
+    /// 在当前账号的用户列表中选中并打开某个会话
+    fn open_contact(&mut self, unique_id: &str) {
+        let idx = self
+            .groups
+            .items
+            .iter()
+            .position(|c| c.unique_id.eq(unique_id));
+        if idx.is_some() {
+            self.groups.state.select(idx);
+            self.open_selected_contact();
+            self.focus = self.tasks.mark;
+        }
+    }
+
+    pub fn on_activity(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// 锁屏，只有配置了解锁口令时才生效
+    pub fn lock(&mut self) {
+        if self.config.lock_passphrase_hash.is_none() {
+            return;
+        }
+        self.locked = true;
+        self.lock_input.clear();
+        self.lock_error = false;
+        self.locked_received = 0;
+        self.input_mode = InputMode::Normal;
+    }
+
+    pub fn on_lock_key(&mut self, c: char) {
+        self.lock_error = false;
+        self.lock_input.push(c);
+    }
+
+    pub fn on_unlock(&mut self) {
+        let passphrase: String = self.lock_input.drain(..).collect();
+        if self.config.check_passphrase(&passphrase) {
+            self.locked = false;
+            self.lock_error = false;
+            // 旧版本保存的不加盐摘要，解锁成功后换成加盐的摘要
+            if self.config.passphrase_hash_outdated() {
+                self.config.lock_passphrase_hash = Some(config::hash_passphrase(&passphrase));
+                if let Err(e) = self.config.save() {
+                    error!("save config error: {}", e);
+                }
+            }
+        } else {
+            self.lock_error = true;
+        }
+    }
+
+    fn check_idle(&mut self) {
+        let timeout = self.config.lock_timeout_secs;
+        if !self.locked && timeout > 0 && self.last_active.elapsed() >= Duration::from_secs(timeout)
+        {
+            self.lock();
+        }
+    }
+
+    pub fn on_up(&mut self) {
+        if let Some(popup) = &mut self.popup {
+            popup.previous();
+            return;
+        }
+        match self.input_mode {
+            InputMode::Editing => {}
+            InputMode::Normal => {
+                if self.focus == self.tasks.mark {
+                    self.tasks.previous();
+                    self.scroll.reveal_selected = true;
+                } else if self.focus == self.groups.mark {
+                    self.groups.previous();
+                }
+            }
+        }
+    }
+
+    pub fn on_down(&mut self) {
+        if let Some(popup) = &mut self.popup {
+            popup.next();
+            return;
+        }
+        match self.input_mode {
+            InputMode::Editing => {}
+            InputMode::Normal => {
+                if self.focus == self.tasks.mark {
+                    self.tasks.next();
+                    self.scroll.reveal_selected = true;
+                } else if self.focus == self.groups.mark {
+                    self.groups.next();
+                }
+            }
+        }
+    }
+
+    pub fn on_page_up(&mut self) {
+        self.scroll.page_up();
+    }
+
+    pub fn on_page_down(&mut self) {
+        self.scroll.page_down();
+    }
+
+    pub fn on_home(&mut self) {
+        if let InputMode::Normal = self.input_mode {
+            self.scroll.top = Some(0);
+        }
+    }
+
+    pub fn on_end(&mut self) {
+        if let InputMode::Normal = self.input_mode {
+            self.scroll.follow();
+        }
+    }
+
+    pub fn on_right(&mut self) {
+        if self.popup.is_some() {
+            return;
+        }
         match self.input_mode {
             InputMode::Editing => {}
             InputMode::Normal => {
@@ -449,6 +1788,9 @@ impl<'a> App<'a> {
     }
 
     pub fn on_left(&mut self) {
+        if self.popup.is_some() {
+            return;
+        }
         match self.input_mode {
             InputMode::Editing => {}
             InputMode::Normal => {
@@ -458,67 +1800,1108 @@ impl<'a> App<'a> {
     }
 
     pub fn on_enter(&mut self) {
+        if self.login_modal {
+            self.dismiss_login_modal();
+            return;
+        }
+        if let Some(popup) = self.popup.take() {
+            self.on_popup_enter(popup);
+            return;
+        }
         match self.input_mode {
             InputMode::Editing => {
-                let msg: String = self.input.drain(..).collect();
-                if msg.len() > 0 {
+                // 保留输入的内容和选好的目标，等上一次群发完成后再发送
+                if self.broadcast_targets.is_some() && self.refuse_if_broadcast_busy() {
+                    return;
+                }
+                let mut msg: String = self.input.drain(..).collect();
+                self.emoji_completion = None;
+                if !msg.starts_with("/send-file ") {
+                    msg = emoji::encode_shortcodes(&msg);
+                }
+                if let Some(targets) = self.broadcast_targets.take() {
+                    if !msg.is_empty() {
+                        self.start_broadcast(msg, targets);
+                    }
+                } else if let Some(path) = msg.strip_prefix("/send-file ") {
+                    match self.target_id.to_owned() {
+                        Some(target_id) => self.send_file(target_id.as_ref(), path.trim()),
+                        None => self.set_status(String::from("请先选择会话")),
+                    }
+                } else if !msg.is_empty() {
                     if let Some(target_id) = self.target_id.to_owned() {
-                        self.message_callback.callback(InputMessage {
-                            message: msg.clone(),
-                            group: target_id.to_string(),
-                        });
-                        //自己发送的数据回显
-                        if !self.message_shard.contains_key(target_id.as_ref()) {
-                            self.message_shard
-                                .insert_new(target_id.as_ref().to_string(), Vec::<Message>::new());
-                        }
-                        if let Some(mut guard) = self.message_shard.get_mut(target_id.as_ref()) {
-                            guard.push(Message {
-                                message: msg,
-                                speaker: String::from("(我)"),
-                            });
-                        }
-                        //刷新界面缓存数据
-                        if let Some(messages) = self.message_shard.get(target_id.as_ref()) {
-                            self.tasks.items = messages.to_vec();
-                            self.tasks.state.select(Some(self.tasks.items.len() - 1));
+                        match self.reply_to.take() {
+                            Some(quoted) => {
+                                let mentions = self.take_mentions(&msg);
+                                self.send_reply(target_id.as_ref(), msg, mentions, &quoted)
+                            }
+                            None => {
+                                let mentions = self.take_mentions(&msg);
+                                self.send_text(InputMessage {
+                                    message: msg,
+                                    group: target_id.to_string(),
+                                    quote: None,
+                                    mentions,
+                                });
+                            }
                         }
+                        self.scroll.follow();
                     }
                 }
             }
             InputMode::Normal => {
                 if self.groups.mark == self.focus {
-                    if let Some(idx) = self.groups.state.selected() {
-                        let unique_id = &self.groups.items[idx].unique_id;
-                        let display_name = &self.groups.items[idx].display_name;
-                        info!("choose target id={}", unique_id);
-                        self.target_id = Some(unique_id.to_owned());
-                        self.target_display_name = Some(display_name.clone());
-                        if let Some(messages) = self.message_shard.get(unique_id.as_ref()) {
-                            self.tasks.items = messages.to_vec();
-                            self.tasks.state.select(Some(self.tasks.items.len() - 1));
+                    self.open_selected_contact();
+                } else if self.tasks.mark == self.focus {
+                    self.open_message_actions();
+                }
+            }
+        }
+    }
+
+    /// 发送文本消息并回显到对应会话，返回请求的 traceId
+    fn send_text(&mut self, input: InputMessage) -> String {
+        let target_id = input.group.clone();
+        let msg = input.message.clone();
+        let quote = input.quote.clone();
+        let trace_id = self.message_callback.callback(input);
+        self.echo_message(
+            &target_id,
+            Message {
+                message: msg,
+                speaker: String::from("(我)"),
+                speaker_id: String::new(),
+                mine: true,
+                msg_id: None,
+                quote,
+                kind: MessageKind::Text,
+                highlight: false,
+                attachment_id: None,
+                recalled: false,
+                trace_id: Some(trace_id.clone()),
+                timestamp: Local::now().timestamp_millis(),
+            },
+        );
+        self.pending_sends.insert(trace_id.clone(), target_id);
+        trace_id
+    }
+
+    /// 自己发送的数据回显
+    fn echo_message(&mut self, target_id: &str, message: Message) {
+        if !self.message_shard.contains_key(target_id) {
+            self.message_shard
+                .insert_new(target_id.to_string(), Vec::<Message>::new());
+        }
+        if let Some(mut guard) = self.message_shard.get_mut(target_id) {
+            guard.push(message);
+        }
+        //刷新界面缓存数据
+        if let Some(unique) = &self.target_id {
+            if unique.eq(target_id) {
+                if let Some(messages) = self.message_shard.get(target_id) {
+                    self.tasks.items = messages.to_vec();
+                    self.tasks.state.select(Some(self.tasks.items.len() - 1));
+                }
+            }
+        }
+    }
+
+    /// `/send-file <path>`：上传本地文件到当前会话
+    fn send_file(&mut self, target_id: &str, path: &str) {
+        let path = match path.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+            None => PathBuf::from(path),
+        };
+        if !self.supports(client::CAP_ATTACHMENTS) {
+            self.set_status(String::from("服务端不支持发送文件"));
+            return;
+        }
+        let size = fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+        match client::send_file(&self.message_callback, path.clone(), target_id) {
+            Ok(trace_id) => {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                self.set_status(format!("正在发送 {}", name));
+                self.pending_uploads.insert(trace_id.clone(), name.clone());
+                self.pending_sends
+                    .insert(trace_id.clone(), target_id.to_owned());
+                self.echo_message(
+                    target_id,
+                    Message {
+                        message: String::new(),
+                        speaker: String::from("(我)"),
+                        speaker_id: String::new(),
+                        mine: true,
+                        msg_id: None,
+                        quote: None,
+                        kind: MessageKind::File { name, size },
+                        highlight: false,
+                        attachment_id: None,
+                        recalled: false,
+                        trace_id: Some(trace_id),
+                        timestamp: Local::now().timestamp_millis(),
+                    },
+                );
+            }
+            Err(e) => {
+                error!("send file {:?} error: {}", path, e);
+                self.set_status(format!("发送文件失败: {}", e));
+            }
+        }
+    }
+
+    fn on_upload_result(&mut self, trace_id: &str, response: &str) {
+        if let Some(name) = self.pending_uploads.remove(trace_id) {
+            match client::parse_json_for_status(response) {
+                Some(result) if result.success => self.set_status(format!("{} 已发送", name)),
+                Some(result) => self.set_status(format!(
+                    "{} 发送失败: {}",
+                    name,
+                    result.message.unwrap_or_default()
+                )),
+                None => error!("parse send file response error: {}", response),
+            }
+        }
+    }
+
+    /// 下载消息的附件，完成后按 `action` 保存、打开或者加载预览
+    fn download_attachment(&mut self, message: &Message, action: DownloadAction) {
+        let attachment_id = match &message.attachment_id {
+            Some(id) => id.clone(),
+            None => return,
+        };
+        if !self.supports(client::CAP_ATTACHMENTS) {
+            if action != DownloadAction::Preview {
+                self.set_status(String::from("服务端不支持附件"));
+            }
+            return;
+        }
+        let dir = match action {
+            DownloadAction::Preview => preview_cache_dir(),
+            _ => self.config.downloads_dir.clone(),
+        };
+        let part_path = dir.join(format!(".{}.part", safe_file_name(&attachment_id)));
+        match client::download_attachment(&self.message_callback, &attachment_id, &part_path) {
+            Ok(trace_id) => {
+                if action != DownloadAction::Preview {
+                    self.set_status(String::from("正在下载附件"));
+                }
+                self.pending_downloads.insert(
+                    trace_id,
+                    PendingDownload {
+                        file_name: attachment_file_name(&message.kind, &attachment_id),
+                        attachment_id,
+                        part_path,
+                        action,
+                    },
+                );
+            }
+            Err(e) => {
+                error!("download attachment {} error: {}", attachment_id, e);
+                if action != DownloadAction::Preview {
+                    self.set_status(format!("下载失败: {}", e));
+                }
+            }
+        }
+    }
+
+    fn on_download_result(&mut self, trace_id: &str, response: &str) {
+        let download = match self.pending_downloads.remove(trace_id) {
+            Some(download) => download,
+            None => return,
+        };
+        client::finish_download(trace_id);
+        let result = match client::parse_json_for_download(response) {
+            Some(result) if result.success => result,
+            result => {
+                let _ = fs::remove_file(&download.part_path);
+                let reason = result.and_then(|r| r.message).unwrap_or_default();
+                if download.action == DownloadAction::Preview {
+                    error!(
+                        "download preview {} error: {}",
+                        download.attachment_id, reason
+                    );
+                    self.continue_previews();
+                } else {
+                    self.set_status(format!("下载失败: {}", reason));
+                }
+                return;
+            }
+        };
+        if download.action == DownloadAction::Preview {
+            let path = preview_cache_dir().join(safe_file_name(&download.attachment_id));
+            match fs::rename(&download.part_path, &path) {
+                Ok(_) => self.decode_preview(download.attachment_id, path),
+                Err(e) => error!("save preview {:?} error: {}", path, e),
+            }
+            self.continue_previews();
+            return;
+        }
+        let file_name = result
+            .data
+            .as_deref()
+            .and_then(download_file_name)
+            .unwrap_or(download.file_name);
+        let path = match unique_path(&self.config.downloads_dir, &file_name) {
+            Some(path) => path,
+            None => {
+                let _ = fs::remove_file(&download.part_path);
+                error!("invalid attachment file name {:?}", file_name);
+                self.set_status(format!("附件文件名无效: {}", file_name));
+                return;
+            }
+        };
+        if let Err(e) = fs::rename(&download.part_path, &path) {
+            error!("save attachment {:?} error: {}", path, e);
+            self.set_status(format!("保存附件失败: {}", e));
+            return;
+        }
+        let path = path.to_string_lossy().to_string();
+        if download.action == DownloadAction::Open {
+            match actions::open_with(self.config.open_command.as_deref(), &path) {
+                Ok(_) => self.set_status(format!("已打开 {}", path)),
+                Err(e) => {
+                    error!("open attachment {} error: {}", path, e);
+                    self.set_status(format!("打开附件失败: {}", e));
+                }
+            }
+        } else {
+            self.set_status(format!("已保存到 {}", path));
+        }
+    }
+
+    /// 为当前会话中最近的图片准备预览。缓存中已有的都直接解码，需要下载的每次只下载
+    /// `PREVIEW_DOWNLOADS` 张，下载完成后再继续下一张
+    fn request_previews(&mut self) {
+        let mut downloading = self
+            .pending_downloads
+            .values()
+            .filter(|d| d.action == DownloadAction::Preview)
+            .count();
+        let images: Vec<Message> = self
+            .tasks
+            .items
+            .iter()
+            .rev()
+            .filter(|m| matches!(m.kind, MessageKind::Image { .. }))
+            .filter(|m| match &m.attachment_id {
+                Some(id) => !self.preview_requested.contains(id),
+                None => false,
+            })
+            .take(PREVIEW_BATCH)
+            .cloned()
+            .collect();
+        for message in images {
+            if !preview_cached(&message) {
+                if downloading >= PREVIEW_DOWNLOADS {
+                    continue;
+                }
+                downloading += 1;
+            }
+            self.request_preview(&message);
+        }
+    }
+
+    /// 准备一张图片的预览：缓存中已有的直接解码，没有的先下载
+    fn request_preview(&mut self, message: &Message) {
+        let attachment_id = message.attachment_id.clone().unwrap_or_default();
+        self.preview_requested.insert(attachment_id.clone());
+        let path = preview_cache_dir().join(safe_file_name(&attachment_id));
+        if path.exists() {
+            self.decode_preview(attachment_id, path);
+        } else {
+            self.download_attachment(message, DownloadAction::Preview);
+        }
+    }
+
+    /// 一张预览下载结束后继续下载下一张
+    fn continue_previews(&mut self) {
+        if self.config.images.inline {
+            self.request_previews();
+        }
+    }
+
+    /// 在后台线程中解码图片，结果在 on_tick 中取回
+    fn decode_preview(&self, attachment_id: String, path: PathBuf) {
+        let sender = self.preview_sender.clone();
+        thread::spawn(move || {
+            let _ = sender.send((attachment_id, preview::load(&path)));
+        });
+    }
+
+    fn receive_previews(&mut self) {
+        while let Ok((attachment_id, result)) = self.preview_receiver.try_recv() {
+            match result {
+                Ok(image) => {
+                    self.previews.insert(attachment_id, image);
+                }
+                Err(e) => error!("decode image {} error: {}", attachment_id, e),
+            }
+        }
+    }
+
+    /// 全屏查看选中的图片
+    fn open_image_viewer(&mut self) {
+        let message = match self.selected_message() {
+            Some(
+                message @ Message {
+                    kind: MessageKind::Image { .. },
+                    attachment_id: Some(_),
+                    ..
+                },
+            ) => message.clone(),
+            _ => return,
+        };
+        let attachment_id = message.attachment_id.clone().unwrap_or_default();
+        if !self.previews.contains_key(&attachment_id) {
+            // 选中的图片不用排队，直接开始加载
+            if !self.preview_requested.contains(&attachment_id) {
+                self.request_preview(&message);
+            }
+            self.set_status(String::from("图片加载中，请稍后再试"));
+            return;
+        }
+        self.viewer = Some(ImageViewer {
+            attachment_id,
+            area: None,
+            drawn: false,
+        });
+    }
+
+    fn close_image_viewer(&mut self) {
+        if self.viewer.take().is_some() {
+            // 图形协议输出的图片不在 tui 的缓冲区中，需要整屏重绘才能盖掉
+            self.redraw = true;
+        }
+    }
+
+    /// 需要通过 kitty/sixel 输出的图片数据和输出位置，每次打开查看器只输出一次
+    pub fn take_graphics(&mut self) -> Option<(Rect, String)> {
+        let viewer = self.viewer.as_mut()?;
+        if viewer.drawn {
+            return None;
+        }
+        let area = viewer.area?;
+        let image = self.previews.get(&viewer.attachment_id)?;
+        viewer.drawn = true;
+        let output = match self.image_protocol {
+            ImageProtocol::Kitty => preview::kitty_sequence(image, area.width, area.height),
+            ImageProtocol::Sixel => preview::sixel_sequence(image, area.width, area.height),
+            _ => return None,
+        };
+        Some((area, output))
+    }
+
+    pub fn on_resize(&mut self) {
+        self.preview_lines.clear();
+        if let Some(viewer) = &mut self.viewer {
+            viewer.drawn = false;
+            self.redraw = true;
+        }
+    }
+
+    /// 引用回复。被引用的消息有服务端 id 且服务端没有拒绝过引用时带上引用信息发送，
+    /// 否则把被引用的内容拼在正文前面作为文本引用
+    fn send_reply(
+        &mut self,
+        target_id: &str,
+        msg: String,
+        mentions: Vec<String>,
+        quoted: &Message,
+    ) {
+        let quote = QuoteRef {
+            msg_id: quoted.msg_id.clone(),
+            display_name: quoted.speaker.clone(),
+            text: quoted.display_text(),
+        };
+        let fallback = quote_as_text(&quote, &msg);
+        if quote.msg_id.is_some()
+            && self.quote_supported != Some(false)
+            && self.supports(client::CAP_QUOTE)
+        {
+            let trace_id = self.send_text(InputMessage {
+                message: msg,
+                group: target_id.to_string(),
+                quote: Some(quote),
+                mentions: mentions.clone(),
+            });
+            self.pending_quotes
+                .insert(trace_id, (target_id.to_string(), fallback, mentions));
+        } else {
+            self.send_text(InputMessage {
+                message: fallback,
+                group: target_id.to_string(),
+                quote: None,
+                mentions,
+            });
+        }
+    }
+
+    /// 发送成功后把服务端分配的消息 id 补到回显的消息上，之后才能撤回
+    fn on_send_result(&mut self, trace_id: &str, response: &str) {
+        let target_id = match self.pending_sends.remove(trace_id) {
+            Some(target_id) => target_id,
+            None => return,
+        };
+        let msg_id = match client::parse_json_for_send(response) {
+            Some(ActionResult {
+                data: Some(Some(msg_id)),
+                ..
+            }) => msg_id,
+            _ => return,
+        };
+        self.update_shard_message(&target_id, |m| {
+            if m.trace_id.as_deref() == Some(trace_id) {
+                m.msg_id = Some(msg_id.clone());
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// 修改会话中第一条满足 `update` 的消息（`update` 返回 true 表示已修改），
+    /// 当前打开的会话同步刷新
+    fn update_shard_message<F>(&mut self, target_id: &str, mut update: F) -> bool
+    where
+        F: FnMut(&mut Message) -> bool,
+    {
+        let updated = match self.message_shard.get_mut(target_id) {
+            Some(mut guard) => guard.iter_mut().rev().any(&mut update),
+            None => false,
+        };
+        let current = self.target_id.as_deref() == Some(target_id);
+        if updated && current {
+            self.tasks.items.iter_mut().rev().any(&mut update);
+        }
+        updated
+    }
+
+    /// 消息被撤回：开启防撤回时只打上标记保留原文，否则替换成一条撤回提示
+    fn mark_recalled(&mut self, target_id: &str, msg_id: &str, name: &str, mine: bool) {
+        let anti_recall = self.config.anti_recall && !mine;
+        let updated = self.update_shard_message(target_id, |m| {
+            if m.msg_id.as_deref() != Some(msg_id) || m.recalled {
+                return false;
+            }
+            m.recalled = true;
+            if !anti_recall {
+                m.message = format!("{} 撤回了一条消息", name);
+                m.kind = MessageKind::System;
+                m.quote = None;
+                m.attachment_id = None;
+                m.highlight = false;
+            }
+            true
+        });
+        if !updated {
+            info!("recalled message {} not found in {}", msg_id, target_id);
+        }
+    }
+
+    /// 撤回自己最近发送的消息
+    fn recall_selected(&mut self, message: &Message) {
+        let (target_id, msg_id) = match (&self.target_id, &message.msg_id) {
+            (Some(target_id), Some(msg_id)) => (target_id.to_string(), msg_id.clone()),
+            _ => return,
+        };
+        let trace_id = client::recall_message(&self.message_callback, &target_id, &msg_id);
+        self.pending_recalls.insert(trace_id, (target_id, msg_id));
+    }
+
+    fn on_recall_result(&mut self, trace_id: &str, response: &str) {
+        if let Some((target_id, msg_id)) = self.pending_recalls.remove(trace_id) {
+            match client::parse_json_for_status(response) {
+                Some(result) if result.success => {
+                    self.mark_recalled(&target_id, &msg_id, "你", true)
+                }
+                result => {
+                    let reason = result.and_then(|r| r.message).unwrap_or_default();
+                    self.set_status(format!("撤回失败: {}", reason));
+                }
+            }
+        }
+    }
+
+    /// 服务端拒绝了带引用的消息，改为文本引用重新发送一次
+    fn on_quote_result(&mut self, trace_id: &str, response: &str) {
+        if let Some((target_id, fallback, mentions)) = self.pending_quotes.remove(trace_id) {
+            match client::parse_json_for_status(response) {
+                Some(result) if result.success => self.quote_supported = Some(true),
+                _ => {
+                    info!(
+                        "quote reply rejected by server, resend as text: {}",
+                        response
+                    );
+                    self.quote_supported = Some(false);
+                    let new_trace = self.message_callback.callback(InputMessage {
+                        message: fallback,
+                        group: target_id.clone(),
+                        quote: None,
+                        mentions,
+                    });
+                    // 回显的消息改为等待重发请求的响应
+                    if let Some(target) = self.pending_sends.remove(trace_id) {
+                        self.pending_sends.insert(new_trace.clone(), target);
+                    }
+                    self.update_shard_message(&target_id, |m| {
+                        if m.trace_id.as_deref() == Some(trace_id) {
+                            m.trace_id = Some(new_trace.clone());
+                            true
                         } else {
-                            self.tasks.items.truncate(0);
-                            self.tasks.state.select(None);
+                            false
                         }
+                    });
+                    self.set_status(String::from("服务端不支持引用回复，已改为文本引用发送"));
+                }
+            }
+        }
+    }
+
+    /// 上一次转发或群发还有消息没有发完
+    fn broadcast_busy(&self) -> bool {
+        match &self.broadcast {
+            Some(broadcast) => broadcast.busy(),
+            None => false,
+        }
+    }
+
+    /// 发完之前不能开始新的群发，否则会丢掉还在排队的消息
+    fn refuse_if_broadcast_busy(&mut self) -> bool {
+        if self.broadcast_busy() {
+            self.set_status(String::from("上一次转发或群发还没有完成"));
+            return true;
+        }
+        false
+    }
+
+    /// 先选择群发的目标，再编辑消息
+    fn open_broadcast_picker(&mut self) {
+        if !self.refuse_if_broadcast_busy() {
+            self.popup = Some(Popup::Recipients(RecipientPicker::new(
+                &self.groups.items,
+                None,
+            )));
+        }
+    }
+
+    fn start_broadcast(&mut self, text: String, targets: Vec<(String, String)>) {
+        if self.refuse_if_broadcast_busy() {
+            return;
+        }
+        let deliveries = targets
+            .into_iter()
+            .map(|(unique_id, display_name)| Delivery {
+                unique_id,
+                display_name,
+                state: DeliveryState::Queued,
+            })
+            .collect();
+        self.broadcast = Some(Broadcast {
+            text,
+            deliveries,
+            last_sent: None,
+        });
+        self.popup = Some(Popup::Broadcast(ListState::default()));
+    }
+
+    /// 按间隔依次发出群发队列中的消息
+    fn pump_broadcast(&mut self) {
+        let expired = match &mut self.broadcast {
+            Some(broadcast) => broadcast.expire(DELIVERY_TIMEOUT),
+            None => return,
+        };
+        if expired {
+            self.report_broadcast_finished();
+        }
+        let interval = Duration::from_millis(self.config.broadcast_interval_ms);
+        let next = match &self.broadcast {
+            Some(broadcast) => {
+                if broadcast
+                    .last_sent
+                    .map(|t| t.elapsed() < interval)
+                    .unwrap_or(false)
+                {
+                    return;
+                }
+                broadcast
+                    .deliveries
+                    .iter()
+                    .position(|d| matches!(d.state, DeliveryState::Queued))
+            }
+            None => return,
+        };
+        if let Some(idx) = next {
+            let (unique_id, text) = match &self.broadcast {
+                Some(b) => (b.deliveries[idx].unique_id.clone(), b.text.clone()),
+                None => return,
+            };
+            let trace_id = self.send_text(InputMessage {
+                message: text,
+                group: unique_id,
+                quote: None,
+                mentions: Vec::new(),
+            });
+            if let Some(broadcast) = &mut self.broadcast {
+                broadcast.deliveries[idx].state = DeliveryState::Sent(trace_id, Instant::now());
+                broadcast.last_sent = Some(Instant::now());
+            }
+        }
+    }
+
+    /// 根据 sendChatMessage 的响应更新群发结果
+    fn on_broadcast_result(&mut self, trace_id: &str, response: &str) {
+        let changed = match &mut self.broadcast {
+            Some(broadcast) => broadcast.on_response(trace_id, response),
+            None => false,
+        };
+        if changed {
+            self.report_broadcast_finished();
+        }
+    }
+
+    /// 在最后一条消息有结果时提示群发完成。完成的群发保留给结果窗口查看，
+    /// 之后的响应不会再匹配到其中的消息，提示只出现一次
+    fn report_broadcast_finished(&mut self) {
+        let (finished, total) = match &self.broadcast {
+            Some(broadcast) => (broadcast.finished(), broadcast.deliveries.len()),
+            None => return,
+        };
+        if finished == total {
+            self.set_status(format!("群发完成 {}/{}", finished, total));
+        }
+    }
+
+    fn selected_message(&self) -> Option<&Message> {
+        self.tasks
+            .state
+            .selected()
+            .and_then(|idx| self.tasks.items.get(idx))
+    }
+
+    fn open_message_actions(&mut self) {
+        if let Some(message) = self.selected_message() {
+            let mut actions = vec![MessageAction::Copy, MessageAction::Reply];
+            // 图片、文件等消息转发出去只是一段占位文字，只允许转发文本消息
+            if let MessageKind::Text = message.kind {
+                actions.push(MessageAction::Forward);
+            }
+            if !message.urls().is_empty() {
+                actions.push(MessageAction::OpenUrl);
+            }
+            if let MessageKind::Location { .. } = message.kind {
+                actions.push(MessageAction::ShowLocation);
+            }
+            if message.mine
+                && self.supports(client::CAP_RECALL)
+                && message.msg_id.is_some()
+                && !message.recalled
+                && Local::now().timestamp_millis() - message.timestamp < RECALL_WINDOW_MS
+            {
+                actions.push(MessageAction::Recall);
+            }
+            let attachments = self.supports(client::CAP_ATTACHMENTS);
+            if let (MessageKind::Image { .. }, Some(_)) = (&message.kind, &message.attachment_id) {
+                actions.push(MessageAction::ViewImage);
+            }
+            if message.attachment_id.is_some() && attachments {
+                actions.push(MessageAction::Download);
+                actions.push(MessageAction::Open);
+            }
+            actions.push(MessageAction::Hide);
+            let mut list = StatefulList::with_items(0, actions);
+            list.state.select(Some(0));
+            self.popup = Some(Popup::MessageActions(list));
+        }
+    }
+
+    fn on_popup_enter(&mut self, popup: Popup) {
+        match popup {
+            Popup::MessageActions(list) => {
+                if let Some(action) = list.state.selected().map(|idx| list.items[idx]) {
+                    self.on_message_action(action);
+                }
+            }
+            Popup::Recipients(picker) => {
+                let targets = picker.targets();
+                if targets.is_empty() {
+                    return;
+                }
+                match picker.message {
+                    Some(message) => self.start_broadcast(message, targets),
+                    None => {
+                        // 先选目标，再编辑要群发的消息
+                        self.set_status(format!("群发给 {} 个联系人，回车发送", targets.len()));
+                        self.broadcast_targets = Some(targets);
+                        self.input_mode = InputMode::Editing;
+                    }
+                }
+            }
+            Popup::Broadcast(_) | Popup::Location(_) => {}
+            Popup::Accounts(list) => {
+                if let Some(idx) = list.state.selected() {
+                    self.switch_account(list.items[idx]);
+                }
+            }
+            Popup::Unread(list) => {
+                if let Some(entry) = list.state.selected().and_then(|idx| list.items.get(idx)) {
+                    self.switch_account(entry.account);
+                    self.open_contact(&entry.unique_id);
+                }
+            }
+            Popup::Emoji(list) => {
+                if let Some(idx) = list.state.selected() {
+                    self.input.push_str(&emoji::code(list.items[idx]));
+                    self.input_mode = InputMode::Editing;
+                }
+            }
+            Popup::OpenUrl(list) => {
+                if let Some(idx) = list.state.selected() {
+                    self.open_url(&list.items[idx]);
+                }
+            }
+        }
+    }
+
+    fn on_message_action(&mut self, action: MessageAction) {
+        let message = match self.selected_message() {
+            Some(message) => message.clone(),
+            None => return,
+        };
+        match action {
+            MessageAction::Copy => match actions::copy_to_clipboard(&message.display_text()) {
+                Ok(_) => self.set_status(String::from("已复制到剪贴板")),
+                Err(e) => error!("copy to clipboard error: {}", e),
+            },
+            MessageAction::Reply => {
+                self.reply_to = Some(message);
+                self.input_mode = InputMode::Editing;
+            }
+            MessageAction::Forward => {
+                if self.refuse_if_broadcast_busy() {
+                    return;
+                }
+                self.popup = Some(Popup::Recipients(RecipientPicker::new(
+                    &self.groups.items,
+                    Some(message.display_text()),
+                )));
+            }
+            MessageAction::OpenUrl => {
+                let urls = message.urls();
+                if urls.len() == 1 {
+                    self.open_url(&urls[0]);
+                } else {
+                    let mut list = StatefulList::with_items(0, urls);
+                    list.state.select(Some(0));
+                    self.popup = Some(Popup::OpenUrl(list));
+                }
+            }
+            MessageAction::Download => self.download_attachment(&message, DownloadAction::Save),
+            MessageAction::Open => self.download_attachment(&message, DownloadAction::Open),
+            MessageAction::ViewImage => self.open_image_viewer(),
+            MessageAction::Recall => self.recall_selected(&message),
+            MessageAction::ShowLocation => {
+                if let MessageKind::Location {
+                    latitude,
+                    longitude,
+                    label,
+                } = message.kind
+                {
+                    self.popup = Some(Popup::Location(LocationView {
+                        latitude,
+                        longitude,
+                        label,
+                        zoom: 2,
+                    }));
+                }
+            }
+            MessageAction::Hide => {
+                // tasks 与当前会话的 message_shard 一一对应，两边同时删除
+                if let (Some(idx), Some(target_id)) = (self.tasks.state.selected(), &self.target_id)
+                {
+                    let emptied = match self.message_shard.get_mut(target_id.as_ref()) {
+                        Some(mut guard) => {
+                            if idx < guard.len() {
+                                guard.remove(idx);
+                            }
+                            guard.is_empty()
+                        }
+                        None => false,
+                    };
+                    // 隐藏了最后一条消息时不保留空的会话记录
+                    if emptied {
+                        self.message_shard.remove(target_id.as_ref());
+                    }
+                    self.tasks.items.remove(idx);
+                    if self.tasks.items.is_empty() {
+                        self.tasks.state.select(None);
                     } else {
-                        return;
+                        self.tasks
+                            .state
+                            .select(Some(idx.min(self.tasks.items.len() - 1)));
                     }
                 }
             }
         }
     }
 
-    pub fn on_esc(&mut self) {
-        match self.input_mode {
-            InputMode::Editing => self.input_mode = InputMode::Normal,
+    fn open_url(&mut self, url: &str) {
+        match actions::open_external(url) {
+            Ok(_) => self.set_status(format!("已打开 {}", url)),
+            Err(e) => {
+                error!("open url {} error: {}", url, e);
+                self.set_status(format!("打开链接失败: {}", e));
+            }
+        }
+    }
+
+    /// 在输入框标题中短暂显示一条提示
+    /// 后台账号的提示前面加上账号名，避免与当前账号混淆
+    pub fn set_status(&mut self, status: String) {
+        let status = if self.in_background {
+            format!("[{}] {}", self.account, status)
+        } else {
+            status
+        };
+        self.status = Some((status, Instant::now()));
+    }
+
+    /// 打开用户列表中当前选中的会话
+    fn open_selected_contact(&mut self) {
+        if let Some(idx) = self.groups.state.selected() {
+            self.pending_mentions.clear();
+            self.completion = None;
+            if self.groups.items[idx].is_group {
+                self.request_group_members(idx);
+            }
+            let unique_id = &self.groups.items[idx].unique_id;
+            let display_name = &self.groups.items[idx].display_name;
+            info!("choose target id={}", unique_id);
+            self.target_id = Some(unique_id.clone());
+            self.message_unread.insert(unique_id.to_string(), 0u16);
+            self.message_mentioned.remove(unique_id.as_ref());
+            self.target_display_name = Some(display_name.to_string());
+            self.scroll.follow();
+            if let Some(messages) = self.message_shard.get(unique_id.as_ref()) {
+                self.tasks.items = messages.to_vec();
+                self.tasks
+                    .state
+                    .select(self.tasks.items.len().checked_sub(1));
+            } else {
+                self.tasks.items.truncate(0);
+                self.tasks.state.select(None);
+            }
+            if self.config.images.inline {
+                self.request_previews();
+            }
+        }
+    }
+
+    fn request_group_members(&mut self, idx: usize) {
+        let group_id = self.groups.items[idx].unique_id.to_string();
+        if !self.supports(client::CAP_GROUP_MEMBERS)
+            || self.group_members.contains_key(&group_id)
+            || self
+                .pending_member_requests
+                .values()
+                .any(|g| *g == group_id)
+        {
+            return;
+        }
+        let trace_id = client::list_group_member(&self.message_callback, &group_id);
+        self.pending_member_requests.insert(trace_id, group_id);
+    }
+
+    /// 在群会话中补全输入框末尾的 `@name`
+    pub fn on_tab(&mut self) {
+        if !matches!(self.input_mode, InputMode::Editing) {
+            return;
+        }
+        if self.complete_emoji() {
+            return;
+        }
+        if let Some(completion) = &mut self.completion {
+            completion.index = (completion.index + 1) % completion.candidates.len();
+            let name = &completion.candidates[completion.index].display_name;
+            self.input.truncate(completion.start);
+            self.input.push('@');
+            self.input.push_str(name);
+            self.input.push(MENTION_SEPARATOR);
+            return;
+        }
+        let members = match self
+            .target_id
+            .as_ref()
+            .and_then(|t| self.group_members.get(t.as_ref()))
+        {
+            Some(members) => members,
+            None => return,
+        };
+        let start = match self.input.rfind('@') {
+            Some(start) => start,
+            None => return,
+        };
+        let prefix = self.input[start + 1..].to_lowercase();
+        if prefix.contains(char::is_whitespace) {
+            return;
+        }
+        let mut candidates: Vec<GroupMember> = members
+            .iter()
+            .filter(|m| m.display_name.to_lowercase().contains(&prefix))
+            .cloned()
+            .collect();
+        if candidates.is_empty() {
+            return;
+        }
+        // 前缀匹配的排在前面
+        candidates.sort_by_key(|m| !m.display_name.to_lowercase().starts_with(&prefix));
+        self.input.truncate(start);
+        self.input.push('@');
+        self.input.push_str(&candidates[0].display_name);
+        self.input.push(MENTION_SEPARATOR);
+        self.completion = Some(Completion {
+            start,
+            candidates,
+            index: 0,
+        });
+    }
+
+    /// `:shortcode` 补全，连续按 Tab 在候选表情之间切换，输入框中插入的是微信表情代码。
+    /// 最后一个词不是 `:` 开头时返回 false，交给 @ 补全处理
+    fn complete_emoji(&mut self) -> bool {
+        if let Some(completion) = &mut self.emoji_completion {
+            completion.index = (completion.index + 1) % completion.candidates.len();
+            self.input.truncate(completion.start);
+            self.input
+                .push_str(&emoji::code(completion.candidates[completion.index]));
+            return true;
+        }
+        let word = self
+            .input
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or_default();
+        let prefix = match word.strip_prefix(':') {
+            Some(prefix) if !prefix.is_empty() && !prefix.contains(':') => prefix,
+            _ => return false,
+        };
+        let candidates = emoji::search(prefix);
+        if candidates.is_empty() {
+            return false;
+        }
+        let start = self.input.len() - word.len();
+        self.input.truncate(start);
+        self.input.push_str(&emoji::code(candidates[0]));
+        self.emoji_completion = Some(EmojiCompletion {
+            start,
+            candidates,
+            index: 0,
+        });
+        true
+    }
+
+    /// 打开表情选择框，选中后把微信表情代码插入输入框
+    pub fn open_emoji_picker(&mut self) {
+        if self.locked || self.viewer.is_some() {
+            return;
+        }
+        let mut list = StatefulList::with_items(0, (0..emoji::EMOJIS.len()).collect());
+        list.state.select(Some(0));
+        self.popup = Some(Popup::Emoji(list));
+    }
+
+    /// 结束补全，把最终选中的成员记为待发送的提及
+    fn finish_completion(&mut self) {
+        self.emoji_completion = None;
+        if let Some(completion) = self.completion.take() {
+            self.pending_mentions
+                .push(completion.candidates[completion.index].clone());
+        }
+    }
+
+    /// 取出正文中仍然保留着的 @ 成员 id
+    fn take_mentions(&mut self, msg: &str) -> Vec<String> {
+        self.finish_completion();
+        let mut ids: Vec<String> = Vec::new();
+        for member in self.pending_mentions.drain(..) {
+            if msg.contains(&format!("@{}", member.display_name))
+                && !ids.contains(&member.unique_id)
+            {
+                ids.push(member.unique_id);
+            }
+        }
+        ids
+    }
+
+    pub fn on_mouse(&mut self, event: MouseEvent) {
+        if self.popup.is_some() || self.login_modal || self.viewer.is_some() {
+            return;
+        }
+        let (column, row) = (event.column, event.row);
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if contains(self.areas.groups, column, row) {
+                    self.input_mode = InputMode::Normal;
+                    self.focus = self.groups.mark;
+                    let list = self.areas.groups_list;
+                    if contains(list, column, row) {
+                        let idx = self.groups.offset + (row - list.y) as usize;
+                        if idx < self.groups.items.len() {
+                            self.groups.state.select(Some(idx));
+                            self.open_selected_contact();
+                        }
+                    }
+                } else if contains(self.areas.conversation, column, row) {
+                    self.input_mode = InputMode::Normal;
+                    self.focus = self.tasks.mark;
+                } else if contains(self.areas.input, column, row) {
+                    self.input_mode = InputMode::Editing;
+                }
+            }
+            MouseEventKind::ScrollDown => {
+                if contains(self.areas.groups, column, row) {
+                    self.groups.scroll_down();
+                } else if contains(self.areas.conversation, column, row) {
+                    self.scroll.scroll_down(3);
+                }
+            }
+            MouseEventKind::ScrollUp => {
+                if contains(self.areas.groups, column, row) {
+                    self.groups.scroll_up();
+                } else if contains(self.areas.conversation, column, row) {
+                    self.scroll.scroll_up(3);
+                }
+            }
             _ => {}
         }
     }
 
+    pub fn on_esc(&mut self) {
+        if self.login_modal {
+            self.login_modal = false;
+            return;
+        }
+        if self.viewer.is_some() {
+            self.close_image_viewer();
+            return;
+        }
+        if self.popup.take().is_some() {
+            return;
+        }
+        if let InputMode::Editing = self.input_mode {
+            self.input_mode = InputMode::Normal;
+            self.reply_to = None;
+            self.broadcast_targets = None;
+            self.finish_completion();
+        }
+    }
+
     pub fn on_backspace(&mut self) {
         match self.input_mode {
             InputMode::Editing => {
+                self.finish_completion();
                 self.input.pop();
             }
             _ => {}
@@ -526,23 +2909,75 @@ impl<'a> App<'a> {
     }
 
     pub fn on_key(&mut self, c: char) {
+        if self.login_modal {
+            self.dismiss_login_modal();
+            return;
+        }
+        if self.viewer.is_some() {
+            // 查看图片时任意键关闭
+            self.close_image_viewer();
+            return;
+        }
+        if let Some(popup) = &mut self.popup {
+            match (popup, c) {
+                (Popup::Recipients(picker), ' ') => picker.toggle(),
+                (Popup::Location(view), '+' | '=') => view.zoom_in(),
+                (Popup::Location(view), '-') => view.zoom_out(),
+                _ => {}
+            }
+            return;
+        }
         match self.input_mode {
             InputMode::Normal => match c {
                 'q' => {
                     self.should_quit = true;
                 }
                 'e' => self.input_mode = InputMode::Editing,
+                'L' => self.lock(),
+                'n' => self.toggle_conversation_notify(),
+                'p' => self.toggle_conversation_flag(true),
+                'm' => self.toggle_conversation_flag(false),
+                'D' => self.toggle_dnd(),
+                'v' => self.toggle_layout(),
+                'A' => self.login_modal = true,
+                'a' => self.open_account_switcher(),
+                'U' => self.open_unread_overview(),
+                '1'..='9' => self.switch_account(c as usize - '1' as usize),
+                'i' if self.focus == self.tasks.mark => self.open_image_viewer(),
+                'B' => self.open_broadcast_picker(),
                 _ => {}
             },
-            InputMode::Editing => self.input.push(c),
+            InputMode::Editing => {
+                self.finish_completion();
+                self.input.push(c);
+            }
         }
     }
 
     pub fn on_tick(&mut self) {
         // self.waiting_message();
         self.receive_push_notification();
+        self.receive_previews();
         self.message_callback.message_dispatch();
         self.dispatch_event();
+        self.check_reconnected();
+        self.check_idle();
+        self.update_title();
+        self.pump_broadcast();
+        self.poll_login();
+        self.with_background_sessions(|app| {
+            app.receive_push_notification();
+            app.message_callback.message_dispatch();
+            app.dispatch_event();
+            app.check_reconnected();
+            app.pump_broadcast();
+            app.poll_login();
+        });
+        if let Some((_, since)) = &self.status {
+            if since.elapsed() >= Duration::from_secs(5) {
+                self.status = None;
+            }
+        }
         // Update progress
         // self.progress += 0.001;
         // if self.progress > 1.0 {
@@ -559,3 +2994,119 @@ impl<'a> App<'a> {
         // self.barchart.insert(0, event);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> MessageKind {
+        MessageKind::File {
+            name: name.to_owned(),
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn download_file_name_stays_in_directory() {
+        assert_eq!(
+            download_file_name("report.pdf").as_deref(),
+            Some("report.pdf")
+        );
+        assert_eq!(
+            download_file_name("../../.bashrc").as_deref(),
+            Some(".bashrc")
+        );
+        assert_eq!(download_file_name("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(
+            download_file_name("..\\..\\evil.exe").as_deref(),
+            Some("evil.exe")
+        );
+        assert_eq!(download_file_name(".."), None);
+        assert_eq!(download_file_name("dir/"), None);
+        assert_eq!(download_file_name(""), None);
+    }
+
+    #[test]
+    fn attachment_file_name_is_sanitized() {
+        assert_eq!(
+            attachment_file_name(&file("../../.bashrc"), "id"),
+            ".bashrc"
+        );
+        assert_eq!(attachment_file_name(&file("/etc/passwd"), "id"), "passwd");
+        assert_eq!(attachment_file_name(&file(".."), "a1"), "a1");
+        assert_eq!(
+            attachment_file_name(
+                &MessageKind::Image {
+                    width: 1,
+                    height: 1
+                },
+                "../x"
+            ),
+            "___x.jpg"
+        );
+        assert_eq!(
+            attachment_file_name(&MessageKind::Text, "/abs/id"),
+            "_abs_id"
+        );
+    }
+
+    #[test]
+    fn unique_path_rejects_names_outside_directory() {
+        let dir = std::env::temp_dir().join("fishloaf-unique-path-test");
+        assert_eq!(unique_path(&dir, "a.txt"), Some(dir.join("a.txt")));
+        assert_eq!(unique_path(&dir, "../a.txt"), None);
+        assert_eq!(unique_path(&dir, "sub/a.txt"), None);
+        assert_eq!(unique_path(&dir, "/etc/passwd"), None);
+        assert_eq!(unique_path(&dir, ".."), None);
+    }
+
+    fn broadcast(states: Vec<DeliveryState>) -> Broadcast {
+        Broadcast {
+            text: String::from("hi"),
+            deliveries: states
+                .into_iter()
+                .map(|state| Delivery {
+                    unique_id: String::from("u"),
+                    display_name: String::from("u"),
+                    state,
+                })
+                .collect(),
+            last_sent: None,
+        }
+    }
+
+    #[test]
+    fn broadcast_only_changes_on_its_own_responses() {
+        let mut broadcast = broadcast(vec![
+            DeliveryState::Delivered,
+            DeliveryState::Sent(String::from("t"), Instant::now()),
+        ]);
+        assert!(broadcast.busy());
+        let ok = r#"{"traceId":"t","method":"sendChatMessage","success":true}"#;
+        assert!(!broadcast.on_response("other", ok));
+        assert!(broadcast.on_response("t", ok));
+        assert_eq!(broadcast.finished(), 2);
+        assert!(!broadcast.busy());
+        // 已经有结果的消息不会再被后续的响应改变
+        assert!(!broadcast.on_response("t", ok));
+    }
+
+    #[test]
+    fn broadcast_gives_up_waiting_for_lost_responses() {
+        let mut broadcast = broadcast(vec![
+            DeliveryState::Queued,
+            DeliveryState::Sent(String::from("t"), Instant::now()),
+        ]);
+        assert!(!broadcast.expire(Duration::from_secs(60)));
+        assert!(broadcast.expire(Duration::ZERO));
+        assert!(matches!(
+            broadcast.deliveries[1].state,
+            DeliveryState::Failed(_)
+        ));
+        assert!(matches!(
+            broadcast.deliveries[0].state,
+            DeliveryState::Queued
+        ));
+        assert!(!broadcast.expire(Duration::ZERO));
+    }
+}
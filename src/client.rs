@@ -1,38 +1,91 @@
-use arc_swap::ArcSwap;
 use chashmap::CHashMap;
 use json::{object::Object, JsonValue};
 use lazy_static::lazy_static;
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet},
     fmt,
+    fs::{self, File},
     io::{self, Read, Write},
-    net::TcpStream,
+    path::{Path, PathBuf},
     result,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use tracing::{error, info};
 use uuid::Uuid;
 
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{error::TryRecvError, Receiver, Sender};
 
-use crate::utils::{build_message, get_message_from_tcpstream_with_protocol};
+use crate::config::{AccountConfig, HeartbeatConfig};
+use crate::transport::{self, Connection};
+use crate::utils::{Frame, FrameError, FrameStream, ProtocolErrors, CHUNK_SIZE};
 
-use self::my_custom_runtime::spawn;
+use self::my_custom_runtime::{spawn, spawn_connection};
 
 use strum_macros::{Display, EnumString};
 
 lazy_static! {
-    pub static ref RESPONSE_WAITING_LIST: ArcSwap<CHashMap<String, String>> =
-        ArcSwap::from(Arc::new(CHashMap::new()));
+    /// 正在下载的附件：traceId -> 临时文件，收到分片时直接写入
+    pub static ref DOWNLOADS: CHashMap<String, File> = CHashMap::new();
 }
 
+/// 变体名就是协议中的方法名，保持与服务端一致
 #[derive(EnumString, Display)]
+#[allow(non_camel_case_types)]
 pub enum ClientMethod {
     listUserAndGroup,
     sendChatMessage,
+    listGroupMember,
+    downloadAttachment,
+    sendFile,
+    recallMessage,
+    getLoginStatus,
+    auth,
+    hello,
+    heartbeat,
 }
 
+/// 客户端实现的协议版本，与服务端不一致时提示
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 附件下载、图片预览和发送文件
+pub const CAP_ATTACHMENTS: &str = "attachments";
+pub const CAP_RECALL: &str = "recall";
+/// 带 msgId 的引用回复，不支持时使用文本引用
+pub const CAP_QUOTE: &str = "quote";
+pub const CAP_GROUP_MEMBERS: &str = "groupMembers";
+/// 查询登录状态和扫码登录
+pub const CAP_LOGIN: &str = "login";
+/// 两个通道上的心跳
+pub const CAP_HEARTBEAT: &str = "heartbeat";
+/// 客户端支持的能力，随 hello 请求告诉服务端
+pub const CAPABILITIES: [&str; 6] = [
+    CAP_ATTACHMENTS,
+    CAP_RECALL,
+    CAP_QUOTE,
+    CAP_GROUP_MEMBERS,
+    CAP_LOGIN,
+    CAP_HEARTBEAT,
+];
+/// 不认识 hello 的旧版本服务端按握手之前的方式使用，心跳需要服务端明确支持
+const LEGACY_CAPABILITIES: [&str; 5] = [
+    CAP_ATTACHMENTS,
+    CAP_RECALL,
+    CAP_QUOTE,
+    CAP_GROUP_MEMBERS,
+    CAP_LOGIN,
+];
+
+/// 连接中断后重试的间隔
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+/// 单连接模式下读取的超时时间，超时后检查有没有要发送的请求
+const MULTIPLEX_POLL: Duration = Duration::from_millis(50);
+
 impl TryInto<ClientMethod> for String {
     type Error = SerializeErr;
 
@@ -51,6 +104,47 @@ pub struct MessageSendRequest {
     pub trace_id: String,
     pub message: String,
     pub target_id: String,
+    pub quote: Option<QuoteRef>,
+    pub at_list: Vec<String>,
+}
+
+/// 引用回复中被引用的消息
+#[derive(Clone)]
+pub struct QuoteRef {
+    pub msg_id: Option<String>,
+    pub display_name: String,
+    pub text: String,
+}
+
+impl From<QuoteRef> for JsonValue {
+    fn from(quote: QuoteRef) -> JsonValue {
+        let mut obj = Object::new();
+        if let Some(msg_id) = quote.msg_id {
+            obj.insert("msgId", json::JsonValue::String(msg_id));
+        }
+        obj.insert("displayName", json::JsonValue::String(quote.display_name));
+        obj.insert("text", json::JsonValue::String(quote.text));
+        JsonValue::Object(obj)
+    }
+}
+
+impl TryFrom<&JsonValue> for QuoteRef {
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        if !value.is_object() {
+            return Err(SerializeErr::FormatError);
+        }
+        Ok(QuoteRef {
+            msg_id: value["msgId"].as_str().map(String::from),
+            display_name: String::from(
+                value["displayName"]
+                    .as_str()
+                    .ok_or(SerializeErr::FieldMissing)?,
+            ),
+            text: String::from(value["text"].as_str().ok_or(SerializeErr::FieldMissing)?),
+        })
+    }
+
+    type Error = SerializeErr;
 }
 
 impl Into<JsonValue> for SystemRequest {
@@ -69,6 +163,31 @@ impl Into<JsonValue> for MessageSendRequest {
         obj.insert("traceId", json::JsonValue::String(self.trace_id));
         obj.insert("message", json::JsonValue::String(self.message));
         obj.insert("targetId", json::JsonValue::String(self.target_id));
+        if let Some(quote) = self.quote {
+            obj.insert("quote", quote.into());
+        }
+        if !self.at_list.is_empty() {
+            obj.insert(
+                "atList",
+                JsonValue::Array(self.at_list.into_iter().map(JsonValue::String).collect()),
+            );
+        }
+        JsonValue::Object(obj)
+    }
+}
+
+pub struct GroupMemberRequest {
+    pub method: String,
+    pub trace_id: String,
+    pub group_id: String,
+}
+
+impl From<GroupMemberRequest> for JsonValue {
+    fn from(request: GroupMemberRequest) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("method", json::JsonValue::String(request.method));
+        obj.insert("traceId", json::JsonValue::String(request.trace_id));
+        obj.insert("groupId", json::JsonValue::String(request.group_id));
         JsonValue::Object(obj)
     }
 }
@@ -92,10 +211,11 @@ impl<T> ActionResult<T> {
         }
     }
 
+    /// 服务端返回失败时使用，调用方根据 `success` 判断，不能只看 `data` 是否为空
     pub fn create_error(error_msg: String, trace_id: String, method: String) -> ActionResult<T> {
         ActionResult {
             data: None,
-            success: true,
+            success: false,
             message: Some(error_msg),
             trace_id,
             method,
@@ -109,11 +229,444 @@ pub struct ContactUserInfo<'a> {
     pub is_group: bool,
 }
 
+/// 群成员
+#[derive(Clone)]
+pub struct GroupMember {
+    pub unique_id: String,
+    pub display_name: String,
+}
+
+impl TryFrom<&JsonValue> for GroupMember {
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        if !value.is_object() {
+            return Err(SerializeErr::FormatError);
+        }
+        Ok(GroupMember {
+            unique_id: String::from(
+                value["uniqueId"]
+                    .as_str()
+                    .ok_or(SerializeErr::FieldMissing)?,
+            ),
+            display_name: String::from(
+                value["displayName"]
+                    .as_str()
+                    .ok_or(SerializeErr::FieldMissing)?,
+            ),
+        })
+    }
+
+    type Error = SerializeErr;
+}
+
+pub struct AttachmentRequest {
+    pub method: String,
+    pub trace_id: String,
+    pub attachment_id: String,
+}
+
+impl From<AttachmentRequest> for JsonValue {
+    fn from(request: AttachmentRequest) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("method", json::JsonValue::String(request.method));
+        obj.insert("traceId", json::JsonValue::String(request.trace_id));
+        obj.insert(
+            "attachmentId",
+            json::JsonValue::String(request.attachment_id),
+        );
+        JsonValue::Object(obj)
+    }
+}
+
+/// 撤回自己发送的消息
+pub struct RecallRequest {
+    pub method: String,
+    pub trace_id: String,
+    pub target_id: String,
+    pub msg_id: String,
+}
+
+impl From<RecallRequest> for JsonValue {
+    fn from(request: RecallRequest) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("method", json::JsonValue::String(request.method));
+        obj.insert("traceId", json::JsonValue::String(request.trace_id));
+        obj.insert("targetId", json::JsonValue::String(request.target_id));
+        obj.insert("msgId", json::JsonValue::String(request.msg_id));
+        JsonValue::Object(obj)
+    }
+}
+
+/// 连接建立后发送的认证请求，携带预共享的令牌
+pub struct AuthRequest {
+    pub method: String,
+    pub trace_id: String,
+    pub token: String,
+}
+
+impl From<AuthRequest> for JsonValue {
+    fn from(request: AuthRequest) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("method", json::JsonValue::String(request.method));
+        obj.insert("traceId", json::JsonValue::String(request.trace_id));
+        obj.insert("token", json::JsonValue::String(request.token));
+        JsonValue::Object(obj)
+    }
+}
+
+/// 撤回通知，推送中 `msgType` 为 `recall`，`msgId` 是被撤回的消息
+pub struct RecallNotice {
+    /// 所在会话
+    pub unique_id: String,
+    pub msg_id: String,
+    /// 撤回消息的人
+    pub display_name: String,
+    /// 是否是自己在其他设备上撤回的
+    pub echo: bool,
+}
+
+impl TryFrom<&JsonValue> for RecallNotice {
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        if value["msgType"].as_str() != Some("recall") {
+            return Err(SerializeErr::FormatError);
+        }
+        Ok(RecallNotice {
+            unique_id: String::from(value["userId"].as_str().ok_or(SerializeErr::FieldMissing)?),
+            msg_id: String::from(value["msgId"].as_str().ok_or(SerializeErr::FieldMissing)?),
+            display_name: String::from(value["displayName"].as_str().unwrap_or_default()),
+            echo: value["echo"].as_bool().unwrap_or(false),
+        })
+    }
+
+    type Error = SerializeErr;
+}
+
+/// 当前登录的微信账号
+#[derive(Clone)]
+pub struct AccountInfo {
+    pub unique_id: String,
+    pub display_name: String,
+    /// 微信号，服务端未提供时为空
+    pub alias: Option<String>,
+    pub signature: Option<String>,
+    pub region: Option<String>,
+}
+
+/// 服务端的登录状态，未登录时带上登录二维码的内容
+#[derive(Clone)]
+pub struct LoginStatus {
+    pub logged_in: bool,
+    pub account: Option<AccountInfo>,
+    pub qrcode: Option<String>,
+}
+
+impl TryFrom<&JsonValue> for LoginStatus {
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        let logged_in = value["loggedIn"]
+            .as_bool()
+            .ok_or(SerializeErr::FieldMissing)?;
+        let account = &value["account"];
+        let account = if account.is_object() {
+            Some(AccountInfo {
+                unique_id: String::from(
+                    account["uniqueId"]
+                        .as_str()
+                        .ok_or(SerializeErr::FieldMissing)?,
+                ),
+                display_name: String::from(
+                    account["displayName"]
+                        .as_str()
+                        .ok_or(SerializeErr::FieldMissing)?,
+                ),
+                alias: account["alias"].as_str().map(String::from),
+                signature: account["signature"].as_str().map(String::from),
+                region: account["region"].as_str().map(String::from),
+            })
+        } else {
+            None
+        };
+        Ok(LoginStatus {
+            logged_in,
+            account,
+            qrcode: value["qrcode"].as_str().map(String::from),
+        })
+    }
+
+    type Error = SerializeErr;
+}
+
+/// 连接后的握手请求，告诉服务端客户端的协议版本和支持的能力
+pub struct HelloRequest {
+    pub method: String,
+    pub trace_id: String,
+    pub protocol_version: u32,
+    pub client_version: String,
+    pub capabilities: Vec<String>,
+}
+
+impl From<HelloRequest> for JsonValue {
+    fn from(request: HelloRequest) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("method", json::JsonValue::String(request.method));
+        obj.insert("traceId", json::JsonValue::String(request.trace_id));
+        obj.insert("protocolVersion", request.protocol_version.into());
+        obj.insert(
+            "clientVersion",
+            json::JsonValue::String(request.client_version),
+        );
+        obj.insert(
+            "capabilities",
+            JsonValue::Array(
+                request
+                    .capabilities
+                    .into_iter()
+                    .map(JsonValue::String)
+                    .collect(),
+            ),
+        );
+        JsonValue::Object(obj)
+    }
+}
+
+/// 服务端对握手的回应
+pub struct ServerHello {
+    /// 0 表示服务端不认识 hello 的旧版本
+    pub protocol_version: u32,
+    pub server_version: Option<String>,
+    pub capabilities: HashSet<String>,
+}
+
+impl ServerHello {
+    /// 旧版本服务端保留握手之前的功能，不支持的请求由服务端返回失败，引用回复有文本回退
+    pub fn legacy() -> ServerHello {
+        ServerHello {
+            protocol_version: 0,
+            server_version: None,
+            capabilities: LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for ServerHello {
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
+        let protocol_version = value["protocolVersion"]
+            .as_u32()
+            .ok_or(SerializeErr::FieldMissing)?;
+        Ok(ServerHello {
+            protocol_version,
+            server_version: value["serverVersion"].as_str().map(String::from),
+            capabilities: value["capabilities"]
+                .members()
+                .filter_map(|c| c.as_str().map(String::from))
+                .collect(),
+        })
+    }
+
+    type Error = SerializeErr;
+}
+
+/// 发送文件的请求，在文件的所有分片发送完之后发出
+pub struct SendFileRequest {
+    pub method: String,
+    pub trace_id: String,
+    pub target_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub chunks: u64,
+}
+
+impl From<SendFileRequest> for JsonValue {
+    fn from(request: SendFileRequest) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("method", json::JsonValue::String(request.method));
+        obj.insert("traceId", json::JsonValue::String(request.trace_id));
+        obj.insert("targetId", json::JsonValue::String(request.target_id));
+        obj.insert("fileName", json::JsonValue::String(request.file_name));
+        obj.insert("fileSize", request.file_size.into());
+        obj.insert("chunks", request.chunks.into());
+        JsonValue::Object(obj)
+    }
+}
+
+/// 消息类型，对应推送中的 `msgType` 字段，缺省为文本
+#[derive(Clone)]
+pub enum MessageKind {
+    Text,
+    Image {
+        width: u32,
+        height: u32,
+    },
+    Voice {
+        duration_secs: u32,
+    },
+    Video {
+        duration_secs: u32,
+    },
+    File {
+        name: String,
+        size: u64,
+    },
+    Link {
+        title: String,
+        url: String,
+    },
+    Sticker,
+    Location {
+        latitude: f64,
+        longitude: f64,
+        label: String,
+    },
+    Transfer {
+        amount: String,
+    },
+    RedPacket,
+    /// 入群、退群、撤回等系统提示
+    System,
+    /// 客户端还不认识的类型，保留服务端给的类型名
+    Unknown(String),
+}
+
+impl MessageKind {
+    /// 非文本消息在聊天窗中显示的标签，文本消息返回 None
+    pub fn label(&self) -> Option<String> {
+        match self {
+            MessageKind::Text | MessageKind::System => None,
+            MessageKind::Image { width, height } if *width > 0 && *height > 0 => {
+                Some(format!("[Image {}x{}]", width, height))
+            }
+            MessageKind::Image { .. } => Some(String::from("[Image]")),
+            MessageKind::Voice { duration_secs } => Some(format!(
+                "[Voice {}:{:02}]",
+                duration_secs / 60,
+                duration_secs % 60
+            )),
+            MessageKind::Video { duration_secs } => Some(format!(
+                "[Video {}:{:02}]",
+                duration_secs / 60,
+                duration_secs % 60
+            )),
+            MessageKind::File { name, size } => {
+                Some(format!("[File {} {}]", name, human_size(*size)))
+            }
+            MessageKind::Link { title, .. } => Some(format!("[Link] {}", title)),
+            MessageKind::Sticker => Some(String::from("[Sticker]")),
+            MessageKind::Location {
+                latitude,
+                longitude,
+                label,
+            } if label.is_empty() => Some(format!(
+                "[Location] {}",
+                format_coordinates(*latitude, *longitude)
+            )),
+            MessageKind::Location {
+                latitude,
+                longitude,
+                label,
+            } => Some(format!(
+                "[Location] {} ({})",
+                label,
+                format_coordinates(*latitude, *longitude)
+            )),
+            MessageKind::Transfer { amount } => Some(format!("[Transfer ¥{}]", amount)),
+            MessageKind::RedPacket => Some(String::from("[Red Packet]")),
+            MessageKind::Unknown(kind) => Some(format!("[{}]", kind)),
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for MessageKind {
+    fn try_from(data: &JsonValue) -> Result<Self, Self::Error> {
+        let kind = match data["msgType"].as_str() {
+            None | Some("text") => MessageKind::Text,
+            Some("image") => MessageKind::Image {
+                width: data["width"].as_u32().unwrap_or(0),
+                height: data["height"].as_u32().unwrap_or(0),
+            },
+            Some("voice") => MessageKind::Voice {
+                duration_secs: data["duration"].as_u32().unwrap_or(0),
+            },
+            Some("video") => MessageKind::Video {
+                duration_secs: data["duration"].as_u32().unwrap_or(0),
+            },
+            Some("file") => MessageKind::File {
+                name: String::from(
+                    data["fileName"]
+                        .as_str()
+                        .ok_or(SerializeErr::FieldMissing)?,
+                ),
+                size: data["fileSize"].as_u64().unwrap_or(0),
+            },
+            Some("link") => MessageKind::Link {
+                title: String::from(data["title"].as_str().unwrap_or_default()),
+                url: String::from(data["url"].as_str().ok_or(SerializeErr::FieldMissing)?),
+            },
+            Some("sticker") => MessageKind::Sticker,
+            Some("location") => MessageKind::Location {
+                latitude: data["latitude"]
+                    .as_f64()
+                    .ok_or(SerializeErr::FieldMissing)?,
+                longitude: data["longitude"]
+                    .as_f64()
+                    .ok_or(SerializeErr::FieldMissing)?,
+                label: String::from(data["label"].as_str().unwrap_or_default()),
+            },
+            Some("transfer") => MessageKind::Transfer {
+                amount: data["amount"]
+                    .as_str()
+                    .map(String::from)
+                    .or_else(|| data["amount"].as_f64().map(|a| format!("{:.2}", a)))
+                    .unwrap_or_default(),
+            },
+            Some("redPacket") => MessageKind::RedPacket,
+            Some("system") => MessageKind::System,
+            Some(other) => MessageKind::Unknown(String::from(other)),
+        };
+        Ok(kind)
+    }
+
+    type Error = SerializeErr;
+}
+
+/// 经纬度显示为 `31.2304°N 121.4737°E`
+pub fn format_coordinates(latitude: f64, longitude: f64) -> String {
+    format!(
+        "{:.4}°{} {:.4}°{}",
+        latitude.abs(),
+        if latitude >= 0.0 { 'N' } else { 'S' },
+        longitude.abs(),
+        if longitude >= 0.0 { 'E' } else { 'W' }
+    )
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", size, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
 pub struct ContactMessage<'a> {
     pub unique_id: Cow<'a, str>,
     pub display_name: Cow<'a, str>,
+    /// 发言人的 id，群消息中用来区分不同成员，服务端未提供时为空
+    pub speaker_id: Option<Cow<'a, str>>,
+    /// 服务端的消息 id，引用回复时需要
+    pub msg_id: Option<Cow<'a, str>>,
     pub text: Cow<'a, str>,
+    pub kind: MessageKind,
     pub echo: bool,
+    pub quote: Option<QuoteRef>,
+    /// 服务端标记的“有人 @ 了我”
+    pub at_me: bool,
+    /// 图片、文件等附件的 id，下载时使用
+    pub attachment_id: Option<Cow<'a, str>>,
 }
 
 pub enum SerializeErr {
@@ -124,6 +677,7 @@ pub enum SerializeErr {
 
 impl<'a> TryFrom<JsonValue> for ContactMessage<'a> {
     fn try_from(value: JsonValue) -> Result<Self, Self::Error> {
+        let kind = MessageKind::try_from(&value)?;
         if let JsonValue::Object(data) = value {
             let echo = data
                 .get("echo")
@@ -136,20 +690,53 @@ impl<'a> TryFrom<JsonValue> for ContactMessage<'a> {
                     .as_str()
                     .ok_or(SerializeErr::FieldFormatError)?,
             );
+            let speaker_id = match data.get("speakerId") {
+                Some(value) => Some(Cow::from(String::from(
+                    value.as_str().ok_or(SerializeErr::FieldFormatError)?,
+                ))),
+                None => None,
+            };
+            let msg_id = match data.get("msgId") {
+                Some(value) => Some(Cow::from(String::from(
+                    value.as_str().ok_or(SerializeErr::FieldFormatError)?,
+                ))),
+                None => None,
+            };
+            let attachment_id = match data.get("attachmentId") {
+                Some(value) => Some(Cow::from(String::from(
+                    value.as_str().ok_or(SerializeErr::FieldFormatError)?,
+                ))),
+                None => None,
+            };
+            let quote = match data.get("quote") {
+                Some(value) => Some(QuoteRef::try_from(value)?),
+                None => None,
+            };
+            let at_me = data
+                .get("atMe")
+                .unwrap_or(&JsonValue::Boolean(false))
+                .as_bool()
+                .ok_or(SerializeErr::FieldFormatError)?;
             return Ok(ContactMessage {
                 unique_id: Cow::from(unique),
+                at_me,
+                speaker_id,
+                msg_id,
+                attachment_id,
+                quote,
                 display_name: Cow::from(String::from(
                     data.get("displayName")
                         .ok_or(SerializeErr::FieldMissing)?
                         .as_str()
                         .ok_or(SerializeErr::FieldFormatError)?,
                 )),
-                text: Cow::from(String::from(
-                    data.get("text")
-                        .ok_or(SerializeErr::FieldMissing)?
-                        .as_str()
-                        .ok_or(SerializeErr::FieldFormatError)?,
-                )),
+                text: Cow::from(String::from(match (&kind, data.get("text")) {
+                    (_, Some(text)) => text.as_str().ok_or(SerializeErr::FieldFormatError)?,
+                    // 只有文本消息要求必须有正文
+                    (MessageKind::Text, None) => return Err(SerializeErr::FieldMissing),
+                    (_, None) => "",
+                })),
+                kind,
                 echo,
             });
         } else {
@@ -194,6 +781,9 @@ impl<'a> TryFrom<JsonValue> for ContactUserInfo<'a> {
 pub struct InputMessage {
     pub message: String,
     pub group: String,
+    pub quote: Option<QuoteRef>,
+    /// 群消息中 @ 到的成员 id
+    pub mentions: Vec<String>,
 }
 
 pub trait ResponseChannel {
@@ -202,7 +792,40 @@ pub trait ResponseChannel {
 
 pub struct MessageChannel {
     pub push_notification_receiver: Receiver<String>,
-    pub message_sender_receiver: (Sender<String>, Receiver<String>),
+    pub message_sender_receiver: (Sender<Frame>, Receiver<String>),
+    /// 收到的请求响应：traceId -> 响应，每个连接单独保存，多个账号之间不会混在一起
+    pub responses: CHashMap<String, String>,
+    pub state: Arc<ConnectionState>,
+}
+
+/// 连接状态，由后台的连接任务更新，界面读取
+#[derive(Default)]
+pub struct ConnectionState {
+    /// 推送通道超时或断开，正在重连
+    pub push_stale: AtomicBool,
+    /// 请求通道超时或断开，正在重连
+    pub message_stale: AtomicBool,
+    /// 服务端支持心跳，握手后由界面设置；不支持时推送通道无法主动探测
+    pub heartbeat: AtomicBool,
+    /// 请求通道重连过，推送通道也需要重连
+    reconnect_push: AtomicBool,
+    /// 请求通道重连成功，界面需要重新握手并刷新用户列表
+    pub reconnected: AtomicBool,
+    /// 两个通道解码出错的次数
+    pub protocol_errors: ProtocolErrors,
+    /// 连接因为重试也无法解决的原因失败，不再重连
+    fatal: Mutex<Option<String>>,
+}
+
+impl ConnectionState {
+    pub fn stale(&self) -> bool {
+        self.push_stale.load(Ordering::Relaxed) || self.message_stale.load(Ordering::Relaxed)
+    }
+
+    /// 连接无法恢复的原因，例如令牌被拒绝或证书校验失败
+    pub fn fatal(&self) -> Option<String> {
+        self.fatal.lock().ok().and_then(|fatal| fatal.clone())
+    }
 }
 
 pub fn list_user_and_group(channel: &MessageChannel) {
@@ -216,12 +839,158 @@ pub fn list_user_and_group(channel: &MessageChannel) {
     );
 }
 
+/// 请求群成员列表，返回请求的 traceId
+pub fn list_group_member(channel: &MessageChannel, group_id: &str) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    channel.send_request(json::stringify(GroupMemberRequest {
+        method: ClientMethod::listGroupMember.to_string(),
+        trace_id: uuid.clone(),
+        group_id: group_id.to_owned(),
+    }));
+    uuid
+}
+
+/// 下载附件：先登记临时文件，服务端的分片直接写入其中，最后一条文本响应表示下载结束。
+/// 返回请求的 traceId
+pub fn download_attachment(
+    channel: &MessageChannel,
+    attachment_id: &str,
+    part_path: &Path,
+) -> io::Result<String> {
+    if let Some(parent) = part_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = File::create(part_path)?;
+    let uuid = Uuid::new_v4().to_string();
+    DOWNLOADS.insert(uuid.clone(), file);
+    channel.send_request(json::stringify(AttachmentRequest {
+        method: ClientMethod::downloadAttachment.to_string(),
+        trace_id: uuid.clone(),
+        attachment_id: attachment_id.to_owned(),
+    }));
+    Ok(uuid)
+}
+
+/// 查询登录状态，返回请求的 traceId
+pub fn get_login_status(channel: &MessageChannel) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    channel.send_request(json::stringify(SystemRequest {
+        method: ClientMethod::getLoginStatus.to_string(),
+        trace_id: uuid.clone(),
+    }));
+    uuid
+}
+
+/// 握手，返回请求的 traceId
+pub fn hello(channel: &MessageChannel) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    channel.send_request(json::stringify(HelloRequest {
+        method: ClientMethod::hello.to_string(),
+        trace_id: uuid.clone(),
+        protocol_version: PROTOCOL_VERSION,
+        client_version: String::from(env!("CARGO_PKG_VERSION")),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    }));
+    uuid
+}
+
+/// 撤回自己发送的消息，返回请求的 traceId
+pub fn recall_message(channel: &MessageChannel, target_id: &str, msg_id: &str) -> String {
+    let uuid = Uuid::new_v4().to_string();
+    channel.send_request(json::stringify(RecallRequest {
+        method: ClientMethod::recallMessage.to_string(),
+        trace_id: uuid.clone(),
+        target_id: target_id.to_owned(),
+        msg_id: msg_id.to_owned(),
+    }));
+    uuid
+}
+
+/// 下载结束（无论成功与否）后关闭临时文件
+pub fn finish_download(trace_id: &str) -> bool {
+    DOWNLOADS.remove(trace_id).is_some()
+}
+
+/// 把收到的分片写入对应的下载文件
+fn write_chunk(meta: &str, data: &[u8]) {
+    let trace_id = match json::parse(meta) {
+        Ok(meta) => match meta["traceId"].as_str() {
+            Some(trace_id) => trace_id.to_owned(),
+            None => return error!("chunk without traceId: {}", meta),
+        },
+        Err(e) => return error!("parse chunk meta error: {}", e),
+    };
+    match DOWNLOADS.get_mut(&trace_id) {
+        Some(mut file) => {
+            if let Err(e) = file.write_all(data) {
+                error!("write attachment {} error: {}", trace_id, e);
+            }
+        }
+        None => info!("drop chunk of unknown download {}", trace_id),
+    }
+}
+
+/// 上传本地文件：按 `CHUNK_SIZE` 分片发送，全部发送完后再发出 `sendFile` 请求。
+/// 文件在后台读取，返回请求的 traceId
+pub fn send_file(channel: &MessageChannel, path: PathBuf, target_id: &str) -> io::Result<String> {
+    let file_size = fs::metadata(&path)?.len();
+    let mut file = File::open(&path)?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let uuid = Uuid::new_v4().to_string();
+    let trace_id = uuid.clone();
+    let target_id = target_id.to_owned();
+    let sender = channel.message_sender_receiver.0.clone();
+    spawn(async move {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut seq = 0u64;
+        let mut remain = file_size;
+        while remain > 0 {
+            let size = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(e) => return error!("read {} error: {}", file_name, e),
+            };
+            remain = remain.saturating_sub(size as u64);
+            let meta = json::object! {
+                traceId: trace_id.as_str(),
+                seq: seq,
+                last: remain == 0,
+            };
+            let frame = Frame::Chunk {
+                meta: json::stringify(meta),
+                data: buffer[..size].to_vec(),
+            };
+            if sender.send(frame).await.is_err() {
+                return;
+            }
+            seq += 1;
+        }
+        let request = json::stringify(SendFileRequest {
+            method: ClientMethod::sendFile.to_string(),
+            trace_id,
+            target_id,
+            file_name,
+            file_size,
+            chunks: seq,
+        });
+        let _ = sender.send(Frame::Text(request)).await;
+    });
+    Ok(uuid)
+}
+
 impl MessageChannel {
-    pub fn new(address: &str, port0: u16, port1: u16) -> MessageChannel {
-        let (push_notification_receiver, message_sender_receiver) = start(address, port0, port1);
+    pub fn new(account: &AccountConfig, heartbeat: HeartbeatConfig) -> MessageChannel {
+        let state = Arc::new(ConnectionState::default());
+        let (push_notification_receiver, message_sender_receiver) =
+            start(account, heartbeat, state.clone());
         MessageChannel {
             push_notification_receiver,
             message_sender_receiver,
+            responses: CHashMap::new(),
+            state,
         }
     }
 
@@ -230,11 +999,9 @@ impl MessageChannel {
         message: String,
         // callback: Box<dyn ResponseChannel + Send + Sync>,
     ) {
-        // RESPONSE_WAITING_LIST.load().insert(uuid.clone(), callback);
         let sender = &self.message_sender_receiver.0;
-        if let Err(e) = sender.blocking_send(message) {
+        if let Err(e) = sender.blocking_send(Frame::Text(message)) {
             error!("error happen! {}", e);
-            // RESPONSE_WAITING_LIST.load().remove(t);
         }
     }
 
@@ -242,23 +1009,27 @@ impl MessageChannel {
         let receiver = &mut self.message_sender_receiver.1;
         if let Ok(message) = receiver.try_recv() {
             if let Some(result) = parse_json_for_trace_id(message.clone()) {
-                RESPONSE_WAITING_LIST.load().insert(result, message);
+                self.responses.insert(result, message);
             }
         }
     }
 
-    pub fn callback(&self, input: InputMessage) {
+    /// 发送聊天消息，返回请求的 traceId
+    pub fn callback(&self, input: InputMessage) -> String {
         let uuid = Uuid::new_v4().to_string();
         let message = json::stringify(MessageSendRequest {
             method: ClientMethod::sendChatMessage.to_string(),
-            trace_id: uuid,
+            trace_id: uuid.clone(),
             message: input.message,
             target_id: input.group,
+            quote: input.quote,
+            at_list: input.mentions,
         });
         let sender = &self.message_sender_receiver.0;
-        if let Err(e) = sender.blocking_send(message) {
+        if let Err(e) = sender.blocking_send(Frame::Text(message)) {
             println!("error happend!{}", e);
         }
+        uuid
     }
 }
 
@@ -293,6 +1064,84 @@ pub(crate) fn parse_json<'a>(message: String) -> Option<ActionResult<Vec<Contact
     return None;
 }
 
+pub(crate) fn parse_json_for_group_member(message: &str) -> Option<ActionResult<Vec<GroupMember>>> {
+    let jvalue = json::parse(message.trim()).ok()?;
+    let trace_id = String::from(jvalue["traceId"].as_str()?);
+    let method = String::from(jvalue["method"].as_str()?);
+    if !jvalue["success"].as_bool()? {
+        let error_msg = String::from(jvalue["message"].as_str().unwrap_or_default());
+        return Some(ActionResult::create_error(error_msg, trace_id, method));
+    }
+    let members = jvalue["data"]
+        .members()
+        .filter_map(|item| GroupMember::try_from(item).ok())
+        .collect();
+    Some(ActionResult::create_success(members, trace_id, method))
+}
+
+/// 解析附件下载的响应，成功时返回服务端给出的文件名（可能为空）
+pub(crate) fn parse_json_for_download(message: &str) -> Option<ActionResult<String>> {
+    let jvalue = json::parse(message.trim()).ok()?;
+    let trace_id = String::from(jvalue["traceId"].as_str()?);
+    let method = String::from(jvalue["method"].as_str()?);
+    if !jvalue["success"].as_bool()? {
+        let error_msg = String::from(jvalue["message"].as_str().unwrap_or_default());
+        return Some(ActionResult::create_error(error_msg, trace_id, method));
+    }
+    let file_name = String::from(jvalue["data"]["fileName"].as_str().unwrap_or_default());
+    Some(ActionResult::create_success(file_name, trace_id, method))
+}
+
+/// 解析发送消息的响应，成功时返回服务端分配的消息 id（服务端没有返回时为空）
+pub(crate) fn parse_json_for_send(message: &str) -> Option<ActionResult<Option<String>>> {
+    let jvalue = json::parse(message.trim()).ok()?;
+    let trace_id = String::from(jvalue["traceId"].as_str()?);
+    let method = String::from(jvalue["method"].as_str()?);
+    if !jvalue["success"].as_bool()? {
+        let error_msg = String::from(jvalue["message"].as_str().unwrap_or_default());
+        return Some(ActionResult::create_error(error_msg, trace_id, method));
+    }
+    let msg_id = jvalue["data"]["msgId"].as_str().map(String::from);
+    Some(ActionResult::create_success(msg_id, trace_id, method))
+}
+
+pub(crate) fn parse_json_for_login(message: &str) -> Option<ActionResult<LoginStatus>> {
+    let jvalue = json::parse(message.trim()).ok()?;
+    let trace_id = String::from(jvalue["traceId"].as_str()?);
+    let method = String::from(jvalue["method"].as_str()?);
+    if !jvalue["success"].as_bool()? {
+        let error_msg = String::from(jvalue["message"].as_str().unwrap_or_default());
+        return Some(ActionResult::create_error(error_msg, trace_id, method));
+    }
+    let status = LoginStatus::try_from(&jvalue["data"]).ok()?;
+    Some(ActionResult::create_success(status, trace_id, method))
+}
+
+pub(crate) fn parse_json_for_hello(message: &str) -> Option<ActionResult<ServerHello>> {
+    let jvalue = json::parse(message.trim()).ok()?;
+    let trace_id = String::from(jvalue["traceId"].as_str()?);
+    let method = String::from(jvalue["method"].as_str()?);
+    if !jvalue["success"].as_bool()? {
+        let error_msg = String::from(jvalue["message"].as_str().unwrap_or_default());
+        return Some(ActionResult::create_error(error_msg, trace_id, method));
+    }
+    let hello = ServerHello::try_from(&jvalue["data"]).ok()?;
+    Some(ActionResult::create_success(hello, trace_id, method))
+}
+
+/// 只解析响应的公共字段，不关心 data 的格式
+pub(crate) fn parse_json_for_status(message: &str) -> Option<ActionResult<()>> {
+    let jvalue = json::parse(message.trim()).ok()?;
+    let trace_id = String::from(jvalue["traceId"].as_str()?);
+    let method = String::from(jvalue["method"].as_str()?);
+    if jvalue["success"].as_bool()? {
+        Some(ActionResult::create_success((), trace_id, method))
+    } else {
+        let error_msg = String::from(jvalue["message"].as_str().unwrap_or_default());
+        Some(ActionResult::create_error(error_msg, trace_id, method))
+    }
+}
+
 pub(crate) fn parse_json_for_trace_id(message: String) -> Option<String> {
     if let Ok(jvalue) = json::parse(message.trim()) {
         if let JsonValue::Object(obj) = jvalue {
@@ -312,6 +1161,16 @@ pub(crate) mod my_custom_runtime {
         EXECUTOR.spawn(f);
     }
 
+    /// 连接的读写会一直阻塞，每个连接单独一个线程，不占用线程池
+    pub fn spawn_connection(f: impl Future<Output = ()> + Send + 'static) {
+        let result = std::thread::Builder::new()
+            .name(String::from("socket-connector"))
+            .spawn(move || EXECUTOR.rt.block_on(f));
+        if let Err(e) = result {
+            tracing::error!("spawn connection thread error: {}", e);
+        }
+    }
+
     pub fn block_on_return<T>(f: impl Future<Output = T> + Send) -> T {
         EXECUTOR.rt.block_on(f)
     }
@@ -344,47 +1203,597 @@ pub(crate) mod my_custom_runtime {
     }
 }
 
-fn createMessagePushClient(address: &str, port: u16) -> Receiver<String> {
-    let mut stream = TcpStream::connect((address, port)).expect("connection failed!");
+/// 建立连接，配置了令牌时先完成认证；开启心跳时设置读写超时，阻塞的读取会定期返回
+fn connect(
+    account: &AccountConfig,
+    port: u16,
+    heartbeat: &HeartbeatConfig,
+) -> io::Result<FrameStream<Connection>> {
+    let connection = transport::connect(&account.target, port, account.tls.as_ref())?;
+    if heartbeat.enabled() {
+        connection.set_timeout(Some(heartbeat.interval()))?;
+    }
+    let mut stream = FrameStream::new(connection);
+    if let Some(token) = &account.token {
+        authenticate(&mut stream, token)?;
+    }
+    Ok(stream)
+}
+
+/// 连接中断后每隔几秒重试一次，直到连上为止。遇到重试也无法解决的错误时返回 `None`
+fn reconnect(
+    account: &AccountConfig,
+    port: u16,
+    heartbeat: &HeartbeatConfig,
+    state: &ConnectionState,
+) -> Option<FrameStream<Connection>> {
+    loop {
+        thread::sleep(RECONNECT_DELAY);
+        match connect(account, port, heartbeat) {
+            Ok(stream) => {
+                info!("reconnected to {}:{}", account.target, port);
+                return Some(stream);
+            }
+            Err(e) => {
+                error!("reconnect to {}:{} error: {}", account.target, port, e);
+                if give_up(state, &e) {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// 连接任务中第一次连接。连不上时标记为中断并进入重连，某个账号的服务端不可用不影响界面和其他账号
+fn first_connect(
+    account: &AccountConfig,
+    port: u16,
+    heartbeat: &HeartbeatConfig,
+    state: &ConnectionState,
+    stale: &AtomicBool,
+) -> Option<FrameStream<Connection>> {
+    match connect(account, port, heartbeat) {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            error!("connect to {}:{} error: {}", account.target, port, e);
+            stale.store(true, Ordering::Relaxed);
+            if give_up(state, &e) {
+                return None;
+            }
+            let stream = reconnect(account, port, heartbeat, state)?;
+            stale.store(false, Ordering::Relaxed);
+            Some(stream)
+        }
+    }
+}
+
+/// 重试也无法解决的错误记录到连接状态中，由界面显示原因，返回是否应该放弃重连
+fn give_up(state: &ConnectionState, error: &io::Error) -> bool {
+    if !transport::is_fatal(error) {
+        return false;
+    }
+    if let Ok(mut fatal) = state.fatal.lock() {
+        fatal.get_or_insert_with(|| error.to_string());
+    }
+    true
+}
+
+/// 连接无法恢复后，之后的请求都直接回应失败，界面不会一直等待
+async fn reject_requests(
+    mut requests: Receiver<Frame>,
+    responses: Sender<String>,
+    state: &ConnectionState,
+) {
+    let reason = state.fatal().unwrap_or_default();
+    while let Some(frame) = requests.recv().await {
+        if let Frame::Text(request) = frame {
+            if let Some(response) = failed_response(&request, &reason) {
+                let _ = responses.send(response).await;
+            }
+        }
+    }
+}
+
+/// 发送认证请求并等待服务端确认，失败时服务端会断开连接
+fn authenticate(stream: &mut FrameStream<Connection>, token: &str) -> io::Result<()> {
+    let request = json::stringify(AuthRequest {
+        method: ClientMethod::auth.to_string(),
+        trace_id: Uuid::new_v4().to_string(),
+        token: token.to_owned(),
+    });
+    stream.write_frame(&Frame::Text(request))?;
+    let response = match stream.read_frame()? {
+        Frame::Text(response) => response,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected frame",
+            ))
+        }
+    };
+    match parse_json_for_status(&response) {
+        Some(result) if result.success => Ok(()),
+        Some(result) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            result
+                .message
+                .unwrap_or_else(|| String::from("authentication failed")),
+        )),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, response)),
+    }
+}
+
+fn heartbeat_request() -> String {
+    json::stringify(SystemRequest {
+        method: ClientMethod::heartbeat.to_string(),
+        trace_id: Uuid::new_v4().to_string(),
+    })
+}
+
+/// 心跳的回应只用来确认连接还活着，不交给界面处理
+fn is_heartbeat(message: &str) -> bool {
+    match json::parse(message.trim()) {
+        Ok(value) => value["method"] == "heartbeat" || value["msgType"] == "heartbeat",
+        Err(_) => false,
+    }
+}
+
+/// 连接断开时代替服务端回应还在等待的请求，界面据此显示发送失败
+fn connection_lost_response(request: &str) -> Option<String> {
+    failed_response(request, "connection lost")
+}
+
+/// 代替服务端生成一个失败的回应
+fn failed_response(request: &str, message: &str) -> Option<String> {
+    let value = json::parse(request.trim()).ok()?;
+    Some(json::stringify(json::object! {
+        traceId: value["traceId"].as_str()?,
+        method: value["method"].as_str().unwrap_or_default(),
+        success: false,
+        message: message,
+    }))
+}
+
+/// 等待一个请求的文本响应，下载附件时先收到的分片直接写入文件。
+/// 返回交给界面的响应以及连接是否还能继续使用
+fn read_response<S: Read>(
+    stream: &mut FrameStream<S>,
+    request: &str,
+    heartbeat: &HeartbeatConfig,
+    errors: &ProtocolErrors,
+) -> (Option<String>, bool) {
+    let mut last_seen = Instant::now();
+    loop {
+        match stream.read_frame() {
+            Ok(Frame::Text(ret_msg)) => return (Some(ret_msg), true),
+            Ok(Frame::Chunk { meta, data }) => {
+                last_seen = Instant::now();
+                write_chunk(&meta, &data);
+            }
+            Ok(Frame::Push(ret_msg)) => {
+                info!("unexpected push on message connection: {}", ret_msg);
+            }
+            // 响应已经从连接上读出，只是无法解码，不再等待，直接回应失败
+            Err(e @ FrameError::InvalidUtf8(_)) => {
+                error!("response protocol error: {}", e);
+                errors.record(&e);
+                return (failed_response(request, "invalid response"), true);
+            }
+            // 其余出错的帧丢弃，继续等待响应，直到超时
+            Err(e) if e.is_protocol() => {
+                error!("response protocol error: {}", e);
+                errors.record(&e);
+            }
+            Err(FrameError::Io(e)) if transport::is_timeout(&e) => {
+                if last_seen.elapsed() >= heartbeat.timeout() {
+                    error!("response timeout");
+                    return (None, false);
+                }
+            }
+            Err(e) => {
+                error!("read response error: {}", e);
+                errors.record(&e);
+                return (None, false);
+            }
+        }
+    }
+}
+
+fn create_message_push_client(
+    account: &AccountConfig,
+    port: u16,
+    heartbeat: HeartbeatConfig,
+    state: Arc<ConnectionState>,
+) -> Receiver<String> {
+    let account = account.clone();
     let (tx, rx) = tokio::sync::mpsc::channel(1024);
-    spawn(async move {
+    spawn_connection(async move {
+        let mut stream = match first_connect(&account, port, &heartbeat, &state, &state.push_stale)
+        {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut last_seen = Instant::now();
+        let mut last_ping = Instant::now();
         loop {
-            let ret_msg = get_message_from_tcpstream_with_protocol(&mut stream);
-            info!("message receive: {}", &ret_msg);
-            let _ = tx.send(ret_msg).await;
+            match stream.read_frame() {
+                Ok(frame) => {
+                    last_seen = Instant::now();
+                    if let Frame::Text(ret_msg) | Frame::Push(ret_msg) = frame {
+                        if !is_heartbeat(&ret_msg) {
+                            info!("message receive: {}", &ret_msg);
+                            let _ = tx.send(ret_msg).await;
+                        }
+                    }
+                    continue;
+                }
+                Err(e) if e.is_protocol() => {
+                    error!("push protocol error: {}", e);
+                    state.protocol_errors.record(&e);
+                    continue;
+                }
+                Err(FrameError::Io(e)) if transport::is_timeout(&e) => {
+                    // 请求通道重连过说明网络断过，推送通道也一起重连
+                    if !state.reconnect_push.swap(false, Ordering::Relaxed) {
+                        if !state.heartbeat.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        if last_seen.elapsed() < heartbeat.timeout() {
+                            if last_ping.elapsed() >= heartbeat.interval() {
+                                last_ping = Instant::now();
+                                let ping = Frame::Text(heartbeat_request());
+                                if let Err(e) = stream.write_frame(&ping) {
+                                    error!("push heartbeat error: {}", e);
+                                    state.push_stale.store(true, Ordering::Relaxed);
+                                }
+                            }
+                            if !state.push_stale.load(Ordering::Relaxed) {
+                                continue;
+                            }
+                        } else {
+                            error!("push connection timeout");
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("read push error: {}", e);
+                    state.protocol_errors.record(&e);
+                }
+            }
+            state.push_stale.store(true, Ordering::Relaxed);
+            stream = match reconnect(&account, port, &heartbeat, &state) {
+                Some(stream) => stream,
+                None => return,
+            };
+            state.push_stale.store(false, Ordering::Relaxed);
+            last_seen = Instant::now();
         }
     });
     return rx;
 }
 
-fn createMessageSendClient(address: &str, port: u16) -> (Sender<String>, Receiver<String>) {
-    let mut stream = TcpStream::connect((address, port)).expect("connection failed!");
-    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(1024);
+fn create_message_send_client(
+    account: &AccountConfig,
+    port: u16,
+    heartbeat: HeartbeatConfig,
+    state: Arc<ConnectionState>,
+) -> (Sender<Frame>, Receiver<String>) {
+    let account = account.clone();
+    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel::<Frame>(1024);
     let (ret_tx, ret_rx) = tokio::sync::mpsc::channel(1024);
-    spawn(async move {
+    spawn_connection(async move {
+        // 连上之前界面发出的请求在队列中等待
+        let mut stream =
+            match first_connect(&account, port, &heartbeat, &state, &state.message_stale) {
+                Some(stream) => stream,
+                None => return reject_requests(msg_rx, ret_tx, &state).await,
+            };
         loop {
-            let msg_opt: Option<String> = msg_rx.recv().await;
-            if let Some(input) = msg_opt {
-                stream.write(&build_message(&input)).expect("write fail");
-                stream.flush().expect("flush error");
+            // 空闲超过一个心跳间隔时发送心跳
+            let frame = if heartbeat.enabled() {
+                match tokio::time::timeout(heartbeat.interval(), msg_rx.recv()).await {
+                    Ok(frame) => frame,
+                    Err(_) if state.heartbeat.load(Ordering::Relaxed) => {
+                        Some(Frame::Text(heartbeat_request()))
+                    }
+                    Err(_) => continue,
+                }
+            } else {
+                msg_rx.recv().await
+            };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => return,
+            };
+            let request = match &frame {
+                Frame::Text(request) => Some(request.clone()),
+                _ => None,
+            };
+            let mut alive = match stream.write_frame(&frame) {
+                Ok(_) => true,
+                Err(e) => {
+                    error!("write request error: {}", e);
+                    false
+                }
+            };
+            // 上传的分片不需要回应，等到最后的文本请求再读取响应
+            if let (true, Some(request)) = (alive, &request) {
+                let (response, ok) =
+                    read_response(&mut stream, request, &heartbeat, &state.protocol_errors);
+                alive = ok;
+                if let Some(response) = response.filter(|response| !is_heartbeat(response)) {
+                    let _ = ret_tx.send(response).await;
+                }
+            }
+            if alive {
+                continue;
+            }
+            if let Some(response) = request.as_deref().and_then(connection_lost_response) {
+                if !is_heartbeat(&response) {
+                    let _ = ret_tx.send(response).await;
+                }
             }
-            let ret_msg = get_message_from_tcpstream_with_protocol(&mut stream);
-            let _ = ret_tx.send(ret_msg).await;
+            state.message_stale.store(true, Ordering::Relaxed);
+            stream = match reconnect(&account, port, &heartbeat, &state) {
+                Some(stream) => stream,
+                None => return reject_requests(msg_rx, ret_tx, &state).await,
+            };
+            state.message_stale.store(false, Ordering::Relaxed);
+            state.reconnect_push.store(true, Ordering::Relaxed);
+            state.reconnected.store(true, Ordering::Relaxed);
         }
     });
     return (msg_tx, ret_rx);
 }
 
+/// 设置较短的读取超时，读取推送的同时可以及时发出请求
+fn set_multiplex_poll(stream: &FrameStream<Connection>) {
+    if let Err(e) = stream.get_ref().set_read_timeout(Some(MULTIPLEX_POLL)) {
+        error!("set read timeout error: {}", e);
+    }
+}
+
+/// 单连接模式：推送和请求共用一个连接，推送使用单独的帧类型，响应按 traceId 对应请求，不需要等上一个请求返回
+fn create_multiplex_client(
+    account: &AccountConfig,
+    port: u16,
+    heartbeat: HeartbeatConfig,
+    state: Arc<ConnectionState>,
+) -> (Receiver<String>, (Sender<Frame>, Receiver<String>)) {
+    let account = account.clone();
+    let (push_tx, push_rx) = tokio::sync::mpsc::channel(1024);
+    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel::<Frame>(1024);
+    let (ret_tx, ret_rx) = tokio::sync::mpsc::channel(1024);
+    spawn_connection(async move {
+        let mut stream =
+            match first_connect(&account, port, &heartbeat, &state, &state.message_stale) {
+                Some(stream) => stream,
+                None => return reject_requests(msg_rx, ret_tx, &state).await,
+            };
+        set_multiplex_poll(&stream);
+        // 已经发出、还没有收到响应的请求，连接断开时逐个返回失败
+        let mut pending: HashMap<String, String> = HashMap::new();
+        let mut last_seen = Instant::now();
+        let mut last_ping = Instant::now();
+        loop {
+            let mut alive = true;
+            while alive {
+                let frame = match msg_rx.try_recv() {
+                    Ok(frame) => frame,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                };
+                if let Frame::Text(request) = &frame {
+                    if let Some(trace_id) = parse_json_for_trace_id(request.clone()) {
+                        pending.insert(trace_id, request.clone());
+                    }
+                }
+                if let Err(e) = stream.write_frame(&frame) {
+                    error!("write request error: {}", e);
+                    alive = false;
+                }
+            }
+            let heartbeat_on = heartbeat.enabled() && state.heartbeat.load(Ordering::Relaxed);
+            if alive
+                && heartbeat_on
+                && last_seen.elapsed() >= heartbeat.interval()
+                && last_ping.elapsed() >= heartbeat.interval()
+            {
+                last_ping = Instant::now();
+                if let Err(e) = stream.write_frame(&Frame::Text(heartbeat_request())) {
+                    error!("heartbeat error: {}", e);
+                    alive = false;
+                }
+            }
+            if alive {
+                match stream.read_frame() {
+                    Ok(frame) => {
+                        last_seen = Instant::now();
+                        match frame {
+                            Frame::Push(ret_msg) => {
+                                if !is_heartbeat(&ret_msg) {
+                                    info!("message receive: {}", &ret_msg);
+                                    let _ = push_tx.send(ret_msg).await;
+                                }
+                            }
+                            Frame::Text(ret_msg) => {
+                                if let Some(trace_id) = parse_json_for_trace_id(ret_msg.clone()) {
+                                    pending.remove(&trace_id);
+                                }
+                                if !is_heartbeat(&ret_msg) {
+                                    let _ = ret_tx.send(ret_msg).await;
+                                }
+                            }
+                            Frame::Chunk { meta, data } => write_chunk(&meta, &data),
+                        }
+                        continue;
+                    }
+                    Err(e) if e.is_protocol() => {
+                        error!("multiplex protocol error: {}", e);
+                        state.protocol_errors.record(&e);
+                        continue;
+                    }
+                    Err(FrameError::Io(e)) if transport::is_timeout(&e) => {
+                        if !heartbeat_on || last_seen.elapsed() < heartbeat.timeout() {
+                            continue;
+                        }
+                        error!("multiplex connection timeout");
+                    }
+                    Err(e) => {
+                        error!("read multiplex error: {}", e);
+                        state.protocol_errors.record(&e);
+                    }
+                }
+            }
+            for (_, request) in pending.drain() {
+                if let Some(response) = connection_lost_response(&request) {
+                    let _ = ret_tx.send(response).await;
+                }
+            }
+            state.message_stale.store(true, Ordering::Relaxed);
+            stream = match reconnect(&account, port, &heartbeat, &state) {
+                Some(stream) => stream,
+                None => return reject_requests(msg_rx, ret_tx, &state).await,
+            };
+            set_multiplex_poll(&stream);
+            state.message_stale.store(false, Ordering::Relaxed);
+            state.reconnected.store(true, Ordering::Relaxed);
+            last_seen = Instant::now();
+        }
+    });
+    (push_rx, (msg_tx, ret_rx))
+}
+
 pub fn start(
-    address: &str,
-    push_listener_port: u16,
-    message_sender_port: u16,
-) -> (Receiver<String>, (Sender<String>, Receiver<String>)) {
+    account: &AccountConfig,
+    heartbeat: HeartbeatConfig,
+    state: Arc<ConnectionState>,
+) -> (Receiver<String>, (Sender<Frame>, Receiver<String>)) {
     //各个地方需要两个端口，一个处理server -> client的推送消息，不需要client回应，另外一个处理client->server的外发消息，需要回应
-    let push_notification_receiver = createMessagePushClient(address, push_listener_port);
-    let (message_sender, response_reciever) = createMessageSendClient(address, message_sender_port);
+    //address 为 unix:/path 时两个通道分别连接 /path.<端口> 这两个 Unix domain socket
+    //配置了令牌时两个连接都要先认证，之后才会请求listUserAndGroup
+    //开启心跳时两个连接都会在超时后自动重连，连接状态记录在state中
+    //配置了port时使用单连接模式，推送和请求共用这一个端口
+    if let Some(port) = account.port {
+        return create_multiplex_client(account, port, heartbeat, state);
+    }
+    let push_notification_receiver =
+        create_message_push_client(account, account.push_port, heartbeat.clone(), state.clone());
+    let (message_sender, response_reciever) =
+        create_message_send_client(account, account.message_port, heartbeat, state);
     return (
         push_notification_receiver,
         (message_sender, response_reciever),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_error_is_not_success() {
+        let result: ActionResult<()> = ActionResult::create_error(
+            String::from("failed"),
+            String::from("trace"),
+            String::from("sendChatMessage"),
+        );
+        assert!(!result.success);
+        assert!(result.data.is_none());
+        assert_eq!(result.message.as_deref(), Some("failed"));
+    }
+
+    #[test]
+    fn failed_responses_are_errors() {
+        let failure = r#"{"traceId":"t","method":"sendChatMessage","success":false,"message":"quote unsupported"}"#;
+        let status = parse_json_for_status(failure).unwrap();
+        assert!(!status.success);
+        assert_eq!(status.message.as_deref(), Some("quote unsupported"));
+        assert!(!parse_json_for_send(failure).unwrap().success);
+        assert!(!parse_json(String::from(failure)).unwrap().success);
+
+        let success =
+            r#"{"traceId":"t","method":"sendChatMessage","success":true,"data":{"msgId":"m"}}"#;
+        let sent = parse_json_for_send(success).unwrap();
+        assert!(sent.success);
+        assert_eq!(sent.data, Some(Some(String::from("m"))));
+    }
+
+    #[test]
+    fn hello_reply_is_parsed() {
+        let reply = r#"{"traceId":"t","method":"hello","success":true,
+            "data":{"protocolVersion":1,"serverVersion":"0.3","capabilities":["quote","recall"]}}"#;
+        let hello = parse_json_for_hello(reply).unwrap().data.unwrap();
+        assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(hello.server_version.as_deref(), Some("0.3"));
+        assert!(hello.capabilities.contains(CAP_QUOTE));
+        assert!(!hello.capabilities.contains(CAP_ATTACHMENTS));
+    }
+
+    #[test]
+    fn hello_version_mismatch_is_kept() {
+        let reply = r#"{"traceId":"t","method":"hello","success":true,
+            "data":{"protocolVersion":2,"capabilities":[]}}"#;
+        let hello = parse_json_for_hello(reply).unwrap().data.unwrap();
+        assert_eq!(hello.protocol_version, 2);
+        assert!(hello.server_version.is_none());
+        assert!(hello.capabilities.is_empty());
+    }
+
+    #[test]
+    fn hello_without_protocol_version_is_rejected() {
+        let data = json::object! { capabilities: ["quote"] };
+        assert!(ServerHello::try_from(&data).is_err());
+        let reply = r#"{"traceId":"t","method":"hello","success":true,"data":{"capabilities":[]}}"#;
+        assert!(parse_json_for_hello(reply).is_none());
+    }
+
+    #[test]
+    fn legacy_reply_keeps_pre_handshake_features() {
+        let reply =
+            r#"{"traceId":"t","method":"hello","success":false,"message":"unknown method"}"#;
+        let result = parse_json_for_hello(reply).unwrap();
+        assert!(!result.success);
+        assert!(result.data.is_none());
+        let legacy = ServerHello::legacy();
+        assert_eq!(legacy.protocol_version, 0);
+        for capability in [
+            CAP_ATTACHMENTS,
+            CAP_RECALL,
+            CAP_QUOTE,
+            CAP_GROUP_MEMBERS,
+            CAP_LOGIN,
+        ] {
+            assert!(legacy.capabilities.contains(capability));
+        }
+        assert!(!legacy.capabilities.contains(CAP_HEARTBEAT));
+    }
+
+    #[test]
+    fn undecodable_response_fails_only_its_request() {
+        let mut bad = crate::utils::build_message("ok");
+        bad[7] = 0xff;
+        let good = r#"{"traceId":"second","success":true}"#;
+        let mut wire = bad;
+        wire.extend(crate::utils::build_message(good));
+        let mut stream = FrameStream::new(io::Cursor::new(wire));
+        let heartbeat = HeartbeatConfig {
+            interval_secs: 0,
+            timeout_secs: 0,
+        };
+        let errors = ProtocolErrors::default();
+
+        let first = r#"{"traceId":"first","method":"sendChatMessage"}"#;
+        let (response, alive) = read_response(&mut stream, first, &heartbeat, &errors);
+        assert!(alive);
+        let status = parse_json_for_status(&response.unwrap()).unwrap();
+        assert!(!status.success);
+        assert_eq!(status.trace_id, "first");
+        assert_eq!(errors.total(), 1);
+
+        let second = r#"{"traceId":"second","method":"sendChatMessage"}"#;
+        let (response, alive) = read_response(&mut stream, second, &heartbeat, &errors);
+        assert!(alive);
+        assert_eq!(response.as_deref(), Some(good));
+    }
+}
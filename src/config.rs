@@ -0,0 +1,1002 @@
+use chrono::{Local, NaiveTime};
+use json::{object::Object, JsonValue};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+use tracing::{error, info};
+
+use crate::transport;
+
+/// 本地配置，保存在 `~/.fishloaf/config.json`，不存在时使用默认值
+pub struct Config {
+    pub path: PathBuf,
+    /// 无操作多少秒后自动锁屏，0 表示不自动锁屏
+    pub lock_timeout_secs: u64,
+    /// 解锁口令加盐的 PBKDF2 摘要，格式见 `hash_passphrase`，为空时锁屏功能不可用
+    pub lock_passphrase_hash: Option<String>,
+    pub notify: NotifyConfig,
+    /// 置顶的会话，始终排在列表最上方
+    pub pinned: HashSet<String>,
+    /// 免打扰的会话，不通知、不因新消息重新排序，未读只显示一个圆点
+    pub muted: HashSet<String>,
+    pub dnd: DndConfig,
+    pub highlight: HighlightConfig,
+    /// 是否捕获鼠标事件，关闭后可以使用终端自带的文本选择
+    pub mouse: bool,
+    pub layout: ConversationLayout,
+    /// 转发、群发时相邻两条消息的最小间隔，避免触发风控
+    pub broadcast_interval_ms: u64,
+    /// 附件下载保存的目录
+    pub downloads_dir: PathBuf,
+    /// 打开附件使用的命令，`{}` 会被替换成文件路径，为空时使用系统默认程序
+    pub open_command: Option<String>,
+    pub images: ImageConfig,
+    /// 防撤回：别人撤回的消息保留原文，以删除线显示
+    pub anti_recall: bool,
+    /// 多个账号各自连接一个 fishloafServer，为空时只使用命令行参数指定的服务端
+    pub accounts: Vec<AccountConfig>,
+    /// 配置文件中无法使用的账号及原因，启动时报错
+    pub account_errors: Vec<String>,
+    /// 无法使用的账号原样保留，保存配置时写回，不会悄悄删掉用户的配置
+    invalid_accounts: Vec<JsonValue>,
+    pub heartbeat: HeartbeatConfig,
+}
+
+/// 心跳：连接空闲时每隔 `interval_secs` 秒发送一次，超过 `timeout_secs` 秒没有收到任何数据
+/// 认为连接已经断开（笔记本休眠、VPN 断开等），标记为中断并重新连接
+#[derive(Clone)]
+pub struct HeartbeatConfig {
+    /// 0 表示不发送心跳，也不检测超时
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl HeartbeatConfig {
+    fn merge(&mut self, value: &JsonValue) {
+        if let Some(interval) = value["intervalSecs"].as_u64() {
+            self.interval_secs = interval;
+        }
+        if let Some(timeout) = value["timeoutSecs"].as_u64() {
+            self.timeout_secs = timeout;
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("intervalSecs", self.interval_secs.into());
+        obj.insert("timeoutSecs", self.timeout_secs.into());
+        JsonValue::Object(obj)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.interval_secs > 0
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    /// 超时至少为两个心跳间隔，避免一次心跳稍有延迟就重连
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.max(self.interval_secs.max(1) * 2))
+    }
+}
+
+/// 一个账号对应的 fishloafServer 地址和端口
+#[derive(Clone)]
+pub struct AccountConfig {
+    /// 账号名称，显示在账号切换和未读汇总中
+    pub name: String,
+    pub target: String,
+    pub push_port: u16,
+    pub message_port: u16,
+    /// 单连接模式的端口，设置后推送和请求共用一个连接，不再使用 `push_port` 和 `message_port`
+    pub port: Option<u16>,
+    /// 为空时使用明文连接
+    pub tls: Option<TlsConfig>,
+    /// 预共享的令牌，连接建立后先发送认证请求
+    pub token: Option<String>,
+}
+
+impl AccountConfig {
+    fn parse(value: &JsonValue) -> Result<AccountConfig, String> {
+        let name = match value["name"].as_str() {
+            Some(name) => name.to_owned(),
+            None => return Err(format!("account without name: {}", value.dump())),
+        };
+        let tls = TlsConfig::parse(&value["tls"]);
+        // 与命令行的 --tls-ca 和 --tls-pin 一样不能同时设置
+        if let Some(TlsConfig {
+            ca_file: Some(_),
+            pin_sha256: Some(_),
+            ..
+        }) = &tls
+        {
+            return Err(format!(
+                "account {}: caFile and pinSha256 cannot be used together",
+                name
+            ));
+        }
+        Ok(AccountConfig {
+            name,
+            target: value["target"].as_str().unwrap_or("127.0.0.1").to_owned(),
+            push_port: value["pushPort"].as_u16().unwrap_or(9022),
+            message_port: value["messagePort"].as_u16().unwrap_or(9021),
+            port: value["port"].as_u16(),
+            tls,
+            token: value["token"].as_str().map(str::to_owned),
+        })
+    }
+
+    /// 令牌是否会以明文经过网络
+    pub fn token_in_cleartext(&self) -> bool {
+        self.token.is_some() && self.tls.is_none() && !transport::is_unix(&self.target)
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("name", self.name.as_str().into());
+        obj.insert("target", self.target.as_str().into());
+        obj.insert("pushPort", self.push_port.into());
+        obj.insert("messagePort", self.message_port.into());
+        if let Some(port) = self.port {
+            obj.insert("port", port.into());
+        }
+        if let Some(tls) = &self.tls {
+            obj.insert("tls", tls.to_json());
+        }
+        if let Some(token) = &self.token {
+            obj.insert("token", token.as_str().into());
+        }
+        JsonValue::Object(obj)
+    }
+}
+
+/// TLS 连接配置：默认用内置的根证书校验服务端证书
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    /// 自签名 CA 的 PEM 文件，设置后只信任该文件中的证书
+    pub ca_file: Option<PathBuf>,
+    /// 服务端证书 DER 编码的 sha256 指纹（十六进制），设置后只接受这一张证书
+    pub pin_sha256: Option<String>,
+    /// 校验证书时使用的域名，为空时使用连接地址
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// `"tls": true` 或者 `"tls": { ... }` 开启 TLS
+    fn parse(value: &JsonValue) -> Option<TlsConfig> {
+        if value.as_bool() == Some(true) {
+            return Some(TlsConfig::default());
+        }
+        if !value.is_object() {
+            return None;
+        }
+        Some(TlsConfig {
+            ca_file: value["caFile"].as_str().map(PathBuf::from),
+            pin_sha256: value["pinSha256"].as_str().map(normalize_fingerprint),
+            server_name: value["serverName"].as_str().map(str::to_owned),
+        })
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        if let Some(ca_file) = &self.ca_file {
+            obj.insert("caFile", ca_file.to_string_lossy().as_ref().into());
+        }
+        if let Some(pin) = &self.pin_sha256 {
+            obj.insert("pinSha256", pin.as_str().into());
+        }
+        if let Some(name) = &self.server_name {
+            obj.insert("serverName", name.as_str().into());
+        }
+        JsonValue::Object(obj)
+    }
+}
+
+/// 证书指纹统一为不带冒号的小写十六进制，方便直接粘贴 openssl 输出的 `AB:CD:...`
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// 全屏查看图片时使用的终端图形协议
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ImageProtocol {
+    /// 根据环境变量判断终端是否支持 kitty 或 sixel，都不支持时使用半块字符
+    Auto,
+    /// Unicode 半块字符 `▀`，每个字符显示上下两个像素，只要求终端支持真彩色
+    HalfBlock,
+    Kitty,
+    Sixel,
+}
+
+/// 图片预览配置
+pub struct ImageConfig {
+    /// 是否在聊天窗中直接显示图片
+    pub inline: bool,
+    /// 聊天窗中一张图片最多占用的行数
+    pub max_rows: u16,
+    pub protocol: ImageProtocol,
+}
+
+impl ImageConfig {
+    fn merge(&mut self, value: &JsonValue) {
+        if let Some(inline) = value["inline"].as_bool() {
+            self.inline = inline;
+        }
+        if let Some(max_rows) = value["maxRows"].as_u16() {
+            self.max_rows = max_rows;
+        }
+        match value["protocol"].as_str() {
+            Some("auto") => self.protocol = ImageProtocol::Auto,
+            Some("halfblock") => self.protocol = ImageProtocol::HalfBlock,
+            Some("kitty") => self.protocol = ImageProtocol::Kitty,
+            Some("sixel") => self.protocol = ImageProtocol::Sixel,
+            _ => {}
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("inline", self.inline.into());
+        obj.insert("maxRows", self.max_rows.into());
+        obj.insert(
+            "protocol",
+            match self.protocol {
+                ImageProtocol::Auto => "auto",
+                ImageProtocol::HalfBlock => "halfblock",
+                ImageProtocol::Kitty => "kitty",
+                ImageProtocol::Sixel => "sixel",
+            }
+            .into(),
+        );
+        JsonValue::Object(obj)
+    }
+}
+
+/// 聊天窗的排版方式
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ConversationLayout {
+    /// 每条消息一行 `speaker >> text`
+    Line,
+    /// 连续消息合并在同一个昵称下，自己的消息靠右
+    Bubble,
+}
+
+/// 高亮规则：关键词（忽略大小写）、正则表达式以及自己的昵称
+pub struct HighlightConfig {
+    pub keywords: Vec<String>,
+    pub patterns: Vec<Regex>,
+    pub my_name: Option<String>,
+}
+
+impl HighlightConfig {
+    fn merge(&mut self, value: &JsonValue) {
+        for keyword in value["keywords"].members() {
+            if let Some(keyword) = keyword.as_str() {
+                self.keywords.push(keyword.to_owned());
+            }
+        }
+        for pattern in value["patterns"].members() {
+            if let Some(pattern) = pattern.as_str() {
+                match Regex::new(pattern) {
+                    Ok(regex) => self.patterns.push(regex),
+                    Err(e) => error!("invalid highlight pattern {}: {}", pattern, e),
+                }
+            }
+        }
+        if let Some(name) = value["myName"].as_str() {
+            self.my_name = Some(name.to_owned());
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert(
+            "keywords",
+            JsonValue::Array(self.keywords.iter().map(|k| k.as_str().into()).collect()),
+        );
+        obj.insert(
+            "patterns",
+            JsonValue::Array(self.patterns.iter().map(|p| p.as_str().into()).collect()),
+        );
+        if let Some(name) = &self.my_name {
+            obj.insert("myName", name.as_str().into());
+        }
+        JsonValue::Object(obj)
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        if let Some(name) = &self.my_name {
+            if text.contains(&format!("@{}", name)) {
+                return true;
+            }
+        }
+        let lower = text.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|k| !k.is_empty() && lower.contains(&k.to_lowercase()))
+            || self.patterns.iter().any(|p| p.is_match(text))
+    }
+}
+
+/// 全局勿扰：手动开启或者按时间段（比如每天站会）开启，期间不发出任何通知
+pub struct DndConfig {
+    pub enabled: bool,
+    pub schedule: Vec<(NaiveTime, NaiveTime)>,
+}
+
+impl DndConfig {
+    fn merge(&mut self, value: &JsonValue) {
+        if let Some(enabled) = value["enabled"].as_bool() {
+            self.enabled = enabled;
+        }
+        for window in value["schedule"].members() {
+            let start = window["start"]
+                .as_str()
+                .and_then(|t| NaiveTime::parse_from_str(t, "%H:%M").ok());
+            let end = window["end"]
+                .as_str()
+                .and_then(|t| NaiveTime::parse_from_str(t, "%H:%M").ok());
+            match (start, end) {
+                (Some(start), Some(end)) => self.schedule.push((start, end)),
+                _ => error!("invalid doNotDisturb schedule: {}", window.dump()),
+            }
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("enabled", self.enabled.into());
+        let schedule: Vec<JsonValue> = self
+            .schedule
+            .iter()
+            .map(|(start, end)| {
+                let mut window = Object::new();
+                window.insert("start", start.format("%H:%M").to_string().into());
+                window.insert("end", end.format("%H:%M").to_string().into());
+                JsonValue::Object(window)
+            })
+            .collect();
+        obj.insert("schedule", JsonValue::Array(schedule));
+        JsonValue::Object(obj)
+    }
+
+    /// 当前是否处于勿扰状态，时间段允许跨越零点
+    pub fn active(&self) -> bool {
+        self.active_at(Local::now().time())
+    }
+
+    fn active_at(&self, now: NaiveTime) -> bool {
+        if self.enabled {
+            return true;
+        }
+        self.schedule.iter().any(|(start, end)| {
+            if start <= end {
+                *start <= now && now < *end
+            } else {
+                now >= *start || now < *end
+            }
+        })
+    }
+}
+
+/// 桌面通知使用的终端转义序列
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DesktopNotify {
+    Off,
+    /// `OSC 9`，iTerm2、Windows Terminal、kitty 等支持
+    Osc9,
+    /// `OSC 777;notify`，urxvt、foot、VTE 系终端支持
+    Osc777,
+}
+
+/// 新消息通知配置
+pub struct NotifyConfig {
+    pub bell: bool,
+    pub desktop: DesktopNotify,
+    /// 在终端窗口标题中显示未读总数
+    pub title: bool,
+    /// 通知中是否带上消息内容
+    pub preview: bool,
+    /// 私聊消息是否通知
+    pub direct: bool,
+    /// 群消息是否通知
+    pub group: bool,
+    /// 单个会话的通知开关，优先于 direct/group
+    pub conversations: HashMap<String, bool>,
+}
+
+impl NotifyConfig {
+    fn merge(&mut self, value: &JsonValue) {
+        if let Some(bell) = value["bell"].as_bool() {
+            self.bell = bell;
+        }
+        match value["desktop"].as_str() {
+            Some("osc9") => self.desktop = DesktopNotify::Osc9,
+            Some("osc777") => self.desktop = DesktopNotify::Osc777,
+            Some("off") => self.desktop = DesktopNotify::Off,
+            _ => {}
+        }
+        if let Some(title) = value["title"].as_bool() {
+            self.title = title;
+        }
+        if let Some(preview) = value["preview"].as_bool() {
+            self.preview = preview;
+        }
+        if let Some(direct) = value["direct"].as_bool() {
+            self.direct = direct;
+        }
+        if let Some(group) = value["group"].as_bool() {
+            self.group = group;
+        }
+        for (unique_id, enabled) in value["conversations"].entries() {
+            if let Some(enabled) = enabled.as_bool() {
+                self.conversations.insert(unique_id.to_owned(), enabled);
+            }
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("bell", self.bell.into());
+        obj.insert(
+            "desktop",
+            match self.desktop {
+                DesktopNotify::Off => "off",
+                DesktopNotify::Osc9 => "osc9",
+                DesktopNotify::Osc777 => "osc777",
+            }
+            .into(),
+        );
+        obj.insert("title", self.title.into());
+        obj.insert("preview", self.preview.into());
+        obj.insert("direct", self.direct.into());
+        obj.insert("group", self.group.into());
+        let mut conversations = Object::new();
+        for (unique_id, enabled) in &self.conversations {
+            conversations.insert(unique_id, (*enabled).into());
+        }
+        obj.insert("conversations", JsonValue::Object(conversations));
+        JsonValue::Object(obj)
+    }
+
+    /// 判断某个会话的新消息是否需要通知
+    pub fn enabled_for(&self, unique_id: &str, is_group: bool) -> bool {
+        match self.conversations.get(unique_id) {
+            Some(enabled) => *enabled,
+            None if is_group => self.group,
+            None => self.direct,
+        }
+    }
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        dirs::home_dir()
+            .unwrap_or_default()
+            .join(".fishloaf")
+            .join("config.json")
+    }
+
+    pub fn load(path: PathBuf) -> Config {
+        let mut config = Config {
+            path,
+            lock_timeout_secs: 0,
+            lock_passphrase_hash: None,
+            notify: NotifyConfig {
+                bell: true,
+                desktop: DesktopNotify::Off,
+                title: true,
+                preview: false,
+                direct: true,
+                group: false,
+                conversations: HashMap::new(),
+            },
+            pinned: HashSet::new(),
+            muted: HashSet::new(),
+            dnd: DndConfig {
+                enabled: false,
+                schedule: Vec::new(),
+            },
+            highlight: HighlightConfig {
+                keywords: Vec::new(),
+                patterns: Vec::new(),
+                my_name: None,
+            },
+            mouse: true,
+            layout: ConversationLayout::Line,
+            broadcast_interval_ms: 1500,
+            downloads_dir: dirs::download_dir()
+                .unwrap_or_else(|| dirs::home_dir().unwrap_or_default().join("Downloads"))
+                .join("fishloaf"),
+            open_command: None,
+            images: ImageConfig {
+                inline: true,
+                max_rows: 12,
+                protocol: ImageProtocol::Auto,
+            },
+            anti_recall: false,
+            accounts: Vec::new(),
+            account_errors: Vec::new(),
+            invalid_accounts: Vec::new(),
+            heartbeat: HeartbeatConfig {
+                interval_secs: 15,
+                timeout_secs: 45,
+            },
+        };
+        match fs::read_to_string(&config.path) {
+            Ok(content) => match json::parse(&content) {
+                Ok(value) => config.merge(&value),
+                Err(e) => error!("parse config {:?} error: {}", config.path, e),
+            },
+            Err(e) => info!("config {:?} not loaded: {}", config.path, e),
+        }
+        config
+    }
+
+    fn merge(&mut self, value: &JsonValue) {
+        if let Some(timeout) = value["lockTimeoutSecs"].as_u64() {
+            self.lock_timeout_secs = timeout;
+        }
+        if let Some(hash) = value["lockPassphraseHash"].as_str() {
+            self.lock_passphrase_hash = Some(hash.to_owned());
+        }
+        self.notify.merge(&value["notify"]);
+        merge_id_set(&mut self.pinned, &value["pinned"]);
+        merge_id_set(&mut self.muted, &value["muted"]);
+        self.dnd.merge(&value["doNotDisturb"]);
+        self.highlight.merge(&value["highlight"]);
+        self.images.merge(&value["images"]);
+        self.heartbeat.merge(&value["heartbeat"]);
+        if let Some(mouse) = value["mouse"].as_bool() {
+            self.mouse = mouse;
+        }
+        if let Some(anti_recall) = value["antiRecall"].as_bool() {
+            self.anti_recall = anti_recall;
+        }
+        for account in value["accounts"].members() {
+            match AccountConfig::parse(account) {
+                Ok(account) => self.accounts.push(account),
+                Err(e) => {
+                    error!("invalid account: {}", e);
+                    self.account_errors.push(e);
+                    self.invalid_accounts.push(account.clone());
+                }
+            }
+        }
+        if let Some(interval) = value["broadcastIntervalMs"].as_u64() {
+            self.broadcast_interval_ms = interval;
+        }
+        if let Some(dir) = value["downloadsDir"].as_str() {
+            self.downloads_dir = PathBuf::from(dir);
+        }
+        if let Some(command) = value["openCommand"].as_str() {
+            self.open_command = Some(command.to_owned());
+        }
+        match value["layout"].as_str() {
+            Some("line") => self.layout = ConversationLayout::Line,
+            Some("bubble") => self.layout = ConversationLayout::Bubble,
+            _ => {}
+        }
+    }
+
+    /// 配置中可能有令牌和口令摘要，文件只允许当前用户读写
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path)?;
+        // mode 只对新建的文件生效，已有的文件也收紧权限
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(json::stringify_pretty(self.to_json(), 2).as_bytes())
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut obj = Object::new();
+        obj.insert("lockTimeoutSecs", self.lock_timeout_secs.into());
+        if let Some(hash) = &self.lock_passphrase_hash {
+            obj.insert("lockPassphraseHash", hash.as_str().into());
+        }
+        obj.insert("notify", self.notify.to_json());
+        obj.insert("pinned", id_set_to_json(&self.pinned));
+        obj.insert("muted", id_set_to_json(&self.muted));
+        obj.insert("doNotDisturb", self.dnd.to_json());
+        obj.insert("highlight", self.highlight.to_json());
+        obj.insert("images", self.images.to_json());
+        obj.insert("heartbeat", self.heartbeat.to_json());
+        obj.insert("mouse", self.mouse.into());
+        obj.insert("antiRecall", self.anti_recall.into());
+        if !self.accounts.is_empty() || !self.invalid_accounts.is_empty() {
+            obj.insert(
+                "accounts",
+                JsonValue::Array(
+                    self.accounts
+                        .iter()
+                        .map(|a| a.to_json())
+                        .chain(self.invalid_accounts.iter().cloned())
+                        .collect(),
+                ),
+            );
+        }
+        obj.insert("broadcastIntervalMs", self.broadcast_interval_ms.into());
+        obj.insert(
+            "downloadsDir",
+            self.downloads_dir.to_string_lossy().as_ref().into(),
+        );
+        if let Some(command) = &self.open_command {
+            obj.insert("openCommand", command.as_str().into());
+        }
+        obj.insert(
+            "layout",
+            match self.layout {
+                ConversationLayout::Line => "line",
+                ConversationLayout::Bubble => "bubble",
+            }
+            .into(),
+        );
+        JsonValue::Object(obj)
+    }
+
+    /// 校验口令是否与配置中的摘要一致
+    pub fn check_passphrase(&self, passphrase: &str) -> bool {
+        match &self.lock_passphrase_hash {
+            Some(hash) => verify_passphrase(hash, passphrase),
+            None => false,
+        }
+    }
+
+    /// 口令摘要是旧版本保存的不加盐 sha256，解锁成功后应当重新计算
+    pub fn passphrase_hash_outdated(&self) -> bool {
+        match &self.lock_passphrase_hash {
+            Some(hash) => !hash.starts_with(PASSPHRASE_SCHEME),
+            None => false,
+        }
+    }
+}
+
+/// 置顶、免打扰等会话开关，返回切换后的状态
+pub fn toggle_id(set: &mut HashSet<String>, unique_id: &str) -> bool {
+    if set.remove(unique_id) {
+        false
+    } else {
+        set.insert(unique_id.to_owned());
+        true
+    }
+}
+
+fn merge_id_set(set: &mut HashSet<String>, value: &JsonValue) {
+    for unique_id in value.members() {
+        if let Some(unique_id) = unique_id.as_str() {
+            set.insert(unique_id.to_owned());
+        }
+    }
+}
+
+fn id_set_to_json(set: &HashSet<String>) -> JsonValue {
+    let mut ids: Vec<&String> = set.iter().collect();
+    ids.sort();
+    JsonValue::Array(ids.into_iter().map(|id| id.as_str().into()).collect())
+}
+
+/// 口令摘要的算法标识
+const PASSPHRASE_SCHEME: &str = "pbkdf2-sha256";
+/// PBKDF2 的迭代次数，让离线猜测口令的代价足够高
+const PASSPHRASE_ITERATIONS: u32 = 600_000;
+
+/// 用随机盐计算口令的 PBKDF2-HMAC-SHA256 摘要，保存为 `pbkdf2-sha256$迭代次数$盐$摘要`，盐和摘要为十六进制
+pub fn hash_passphrase(passphrase: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    format_passphrase_hash(passphrase, &salt, PASSPHRASE_ITERATIONS)
+}
+
+fn format_passphrase_hash(passphrase: &str, salt: &[u8], iterations: u32) -> String {
+    let hash = pbkdf2_sha256(passphrase.as_bytes(), salt, iterations);
+    format!(
+        "{}${}${}${}",
+        PASSPHRASE_SCHEME,
+        iterations,
+        to_hex(salt),
+        to_hex(&hash)
+    )
+}
+
+/// 按保存的迭代次数和盐重新计算摘要再比较。旧版本保存的不加盐 sha256 摘要仍然可以解锁
+fn verify_passphrase(hash: &str, passphrase: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    let expected = match parts.as_slice() {
+        [scheme, iterations, salt, _] if *scheme == PASSPHRASE_SCHEME => {
+            match (iterations.parse::<u32>(), from_hex(salt)) {
+                (Ok(iterations), Some(salt)) if iterations > 0 => {
+                    format_passphrase_hash(passphrase, &salt, iterations)
+                }
+                _ => return false,
+            }
+        }
+        [_] => to_hex(&Sha256::digest(passphrase.as_bytes())),
+        _ => return false,
+    };
+    constant_time_eq(hash.to_ascii_lowercase().as_bytes(), expected.as_bytes())
+}
+
+/// PBKDF2-HMAC-SHA256（RFC 8018），只取第一块 32 字节输出
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    // HMAC 的密钥超过块长度时先做一次摘要，不足的部分补 0
+    let mut key = [0u8; 64];
+    if password.len() > key.len() {
+        key[..32].copy_from_slice(&Sha256::digest(password));
+    } else {
+        key[..password.len()].copy_from_slice(password);
+    }
+    let inner = Sha256::new().chain_update(key.map(|b| b ^ 0x36));
+    let outer = Sha256::new().chain_update(key.map(|b| b ^ 0x5c));
+    let hmac = |data: &[u8]| -> [u8; 32] {
+        let inner_hash = inner.clone().chain_update(data).finalize();
+        outer.clone().chain_update(inner_hash).finalize().into()
+    };
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac(&block);
+    let mut out = u;
+    for _ in 1..iterations {
+        u = hmac(&u);
+        for (o, b) in out.iter_mut().zip(u.iter()) {
+            *o ^= b;
+        }
+    }
+    out
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)
+                .ok()
+                .filter(|p| p.len() == 2 && p.bytes().all(|b| b.is_ascii_hexdigit()))?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// 比较时不提前返回，耗时与相同前缀的长度无关
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn dnd(schedule: JsonValue) -> DndConfig {
+        let mut dnd = DndConfig {
+            enabled: false,
+            schedule: Vec::new(),
+        };
+        dnd.merge(&json::object! { schedule: schedule });
+        dnd
+    }
+
+    fn highlight(value: JsonValue) -> HighlightConfig {
+        let mut highlight = HighlightConfig {
+            keywords: Vec::new(),
+            patterns: Vec::new(),
+            my_name: None,
+        };
+        highlight.merge(&value);
+        highlight
+    }
+
+    #[test]
+    fn highlight_keyword_ignores_case() {
+        let highlight = highlight(json::object! { keywords: ["OnCall", ""] });
+        assert!(highlight.matches("今天谁 oncall"));
+        assert!(highlight.matches("ONCALL 轮值"));
+        assert!(!highlight.matches("on call"));
+    }
+
+    #[test]
+    fn highlight_invalid_pattern_is_skipped() {
+        let highlight = highlight(json::object! { patterns: ["(unclosed", "(?i)fish\\w+"] });
+        assert_eq!(highlight.patterns.len(), 1);
+        assert!(highlight.matches("FishLoaf 发布了"));
+        assert!(!highlight.matches("(unclosed"));
+    }
+
+    #[test]
+    fn highlight_mentions_my_name() {
+        let highlight = highlight(json::object! { myName: "张三" });
+        assert!(highlight.matches("@张三 看一下"));
+        assert!(!highlight.matches("张三 看一下"));
+        assert!(!highlight.matches("@李四"));
+    }
+
+    #[test]
+    fn dnd_window_within_day() {
+        let dnd = dnd(json::array![{ start: "09:30", end: "09:45" }]);
+        assert!(!dnd.active_at(time(9, 29)));
+        assert!(dnd.active_at(time(9, 30)));
+        assert!(dnd.active_at(time(9, 44)));
+        assert!(!dnd.active_at(time(9, 45)));
+    }
+
+    #[test]
+    fn dnd_window_wraps_past_midnight() {
+        let dnd = dnd(json::array![{ start: "22:00", end: "07:00" }]);
+        assert!(dnd.active_at(time(23, 0)));
+        assert!(dnd.active_at(time(0, 0)));
+        assert!(dnd.active_at(time(6, 59)));
+        assert!(!dnd.active_at(time(7, 0)));
+        assert!(!dnd.active_at(time(12, 0)));
+        assert!(!dnd.active_at(time(21, 59)));
+    }
+
+    #[test]
+    fn dnd_invalid_window_is_skipped() {
+        let dnd = dnd(json::array![
+            { start: "25:00", end: "07:00" },
+            { start: "12:00" },
+            { start: "12:00", end: "13:00" }
+        ]);
+        assert_eq!(dnd.schedule.len(), 1);
+        assert!(dnd.active_at(time(12, 30)));
+    }
+
+    #[test]
+    fn dnd_enabled_overrides_schedule() {
+        let mut dnd = dnd(json::array![]);
+        assert!(!dnd.active_at(time(12, 0)));
+        dnd.enabled = true;
+        assert!(dnd.active_at(time(12, 0)));
+    }
+
+    #[test]
+    fn account_rejects_ca_file_with_pin() {
+        let both = json::object! {
+            name: "work",
+            tls: { caFile: "/path/ca.pem", pinSha256: "ab:cd" },
+        };
+        assert!(AccountConfig::parse(&both).is_err());
+        let pin = json::object! { name: "work", tls: { pinSha256: "ab:cd" } };
+        assert!(AccountConfig::parse(&pin).is_ok());
+        assert!(AccountConfig::parse(&json::object! { target: "x" }).is_err());
+    }
+
+    #[test]
+    fn tls_accepts_bool_or_object() {
+        assert!(TlsConfig::parse(&JsonValue::Boolean(true)).is_some());
+        assert!(TlsConfig::parse(&JsonValue::Boolean(false)).is_none());
+        assert!(TlsConfig::parse(&JsonValue::Null).is_none());
+        let tls = TlsConfig::parse(&json::object! {
+            caFile: "/path/ca.pem",
+            serverName: "chat.example.com",
+        })
+        .unwrap();
+        assert_eq!(tls.ca_file, Some(PathBuf::from("/path/ca.pem")));
+        assert_eq!(tls.server_name.as_deref(), Some("chat.example.com"));
+        assert!(tls.pin_sha256.is_none());
+        let tls = TlsConfig::parse(&json::object! { pinSha256: "AB:cd:0F" }).unwrap();
+        assert_eq!(tls.pin_sha256.as_deref(), Some("abcd0f"));
+    }
+
+    #[test]
+    fn fingerprint_is_normalized() {
+        assert_eq!(normalize_fingerprint("AB:CD:EF:01"), "abcdef01");
+        assert_eq!(normalize_fingerprint("abcdef01"), "abcdef01");
+    }
+
+    #[test]
+    fn token_in_cleartext_only_over_plain_tcp() {
+        let account = |value: JsonValue| AccountConfig::parse(&value).unwrap();
+        assert!(account(json::object! { name: "a", token: "t" }).token_in_cleartext());
+        assert!(!account(json::object! { name: "a", token: "t", tls: true }).token_in_cleartext());
+        assert!(!account(json::object! {
+            name: "a",
+            token: "t",
+            target: "unix:/run/user/1000/fishloaf",
+        })
+        .token_in_cleartext());
+        assert!(!account(json::object! { name: "a" }).token_in_cleartext());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_config_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("fishloaf-config-test-{}", std::process::id()));
+        let path = dir.join("config.json");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        Config::load(path.clone()).save().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn invalid_accounts_survive_save() {
+        let mut config = Config::load(PathBuf::from("/nonexistent/config.json"));
+        let invalid = json::object! { target: "no-name", tls: { caFile: "a", pinSha256: "b" } };
+        config.merge(&json::object! {
+            accounts: [{ name: "work", target: "chat.example.com" }, invalid.clone()],
+        });
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.account_errors.len(), 1);
+        let accounts = &config.to_json()["accounts"];
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0]["name"], "work");
+        assert_eq!(accounts[1], invalid);
+    }
+
+    #[test]
+    fn pbkdf2_matches_rfc_vectors() {
+        // RFC 7914 第 11 节的 PBKDF2-HMAC-SHA256 测试向量
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"password", b"salt", 1)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"password", b"salt", 2)),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+    }
+
+    #[test]
+    fn passphrase_hash_is_salted_and_verifiable() {
+        let hash = format_passphrase_hash("secret", b"0123456789abcdef", 10);
+        assert!(hash.starts_with("pbkdf2-sha256$10$30313233"));
+        assert!(verify_passphrase(&hash, "secret"));
+        assert!(!verify_passphrase(&hash, "Secret"));
+        let other = format_passphrase_hash("secret", b"fedcba9876543210", 10);
+        assert_ne!(hash, other);
+        assert!(verify_passphrase(&other, "secret"));
+        assert!(!verify_passphrase("pbkdf2-sha256$0$00$00", "secret"));
+        assert!(!verify_passphrase("pbkdf2-sha256$10$zz$00", "secret"));
+    }
+
+    #[test]
+    fn legacy_passphrase_hash_still_unlocks() {
+        let legacy = to_hex(&Sha256::digest(b"secret")).to_uppercase();
+        assert!(verify_passphrase(&legacy, "secret"));
+        assert!(!verify_passphrase(&legacy, "other"));
+        let mut config = Config::load(PathBuf::from("/nonexistent/config.json"));
+        config.lock_passphrase_hash = Some(legacy);
+        assert!(config.passphrase_hash_outdated());
+        config.lock_passphrase_hash = Some(format_passphrase_hash("secret", b"salt", 1));
+        assert!(!config.passphrase_hash_outdated());
+    }
+}
@@ -1,12 +1,18 @@
-use crate::{app::App, client::MessageChannel, ui};
+use crate::{
+    app::App,
+    client::MessageChannel,
+    config::{AccountConfig, Config, ImageProtocol},
+    preview, ui,
+};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    cursor::MoveTo,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use std::{
     error::Error,
-    io,
+    io::{self, Write},
     time::{Duration, Instant},
 };
 use tui::{
@@ -17,31 +23,56 @@ use tui::{
 pub fn run(
     tick_rate: Duration,
     enhanced_graphics: bool,
-    address: &str,
-    port_pair: (u16, u16),
+    accounts: Vec<AccountConfig>,
+    config: Config,
 ) -> Result<(), Box<dyn Error>> {
+    // 在进入终端界面之前检查，出错时终端不会停留在 raw mode
+    let cleartext: Vec<String> = accounts
+        .iter()
+        .filter(|account| account.token_in_cleartext())
+        .map(|account| account.name.clone())
+        .collect();
+    let mut accounts = accounts.into_iter();
+    let first = accounts.next().ok_or("no account configured")?;
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let mouse = config.mouse;
+    execute!(stdout, EnterAlternateScreen)?;
+    if mouse {
+        execute!(stdout, EnableMouseCapture)?;
+    }
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run it
-    let app = App::new(
+    let heartbeat = config.heartbeat.clone();
+    let mut app = App::new(
         "Crossterm Demo",
         enhanced_graphics,
-        MessageChannel::new(address, port_pair.0, port_pair.1),
+        MessageChannel::new(&first, heartbeat.clone()),
+        config,
     );
+    app.account = first.name;
+    for account in accounts {
+        let channel = MessageChannel::new(&account, heartbeat.clone());
+        app.add_account(account.name, channel);
+    }
+    if !cleartext.is_empty() {
+        app.set_status(format!(
+            "警告：账号 {} 的令牌未经TLS加密发送",
+            cleartext.join("、")
+        ));
+    }
     let res = run_app(&mut terminal, app, tick_rate);
 
     // restore terminal
     disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    if mouse {
+        execute!(terminal.backend_mut(), DisableMouseCapture)?;
+    }
     terminal.show_cursor()?;
 
     if let Err(err) = res {
@@ -57,26 +88,73 @@ fn run_app<B: Backend>(
     tick_rate: Duration,
 ) -> io::Result<()> {
     let mut last_tick = Instant::now();
-    app.refresh_contact_list();
+    app.connect_accounts();
     loop {
+        if app.redraw {
+            app.redraw = false;
+            if app.image_protocol == ImageProtocol::Kitty {
+                let mut stdout = io::stdout();
+                stdout.write_all(preview::kitty_clear().as_bytes())?;
+                stdout.flush()?;
+            }
+            terminal.clear()?;
+        }
         terminal.draw(|f| ui::draw(f, &mut app))?;
+        if let Some((area, graphics)) = app.take_graphics() {
+            // 图片不经过 tui 的缓冲区，直接输出到绘制好的空白区域
+            let mut stdout = io::stdout();
+            execute!(stdout, MoveTo(area.x, area.y))?;
+            stdout.write_all(graphics.as_bytes())?;
+            stdout.flush()?;
+        }
 
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
             .unwrap_or_else(|| Duration::from_secs(0));
         if crossterm::event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char(c) => app.on_key(c),
-                    KeyCode::Left => app.on_left(),
-                    KeyCode::Up => app.on_up(),
-                    KeyCode::Right => app.on_right(),
-                    KeyCode::Down => app.on_down(),
-                    KeyCode::Esc => app.on_esc(),
-                    KeyCode::Enter => app.on_enter(),
-                    KeyCode::Backspace => app.on_backspace(),
-                    _ => {}
+            match event::read()? {
+                Event::Key(key) => {
+                    app.on_activity();
+                    if app.locked {
+                        match key.code {
+                            KeyCode::Char(c) => app.on_lock_key(c),
+                            KeyCode::Enter => app.on_unlock(),
+                            KeyCode::Backspace => {
+                                app.lock_input.pop();
+                            }
+                            KeyCode::Esc => app.lock_input.clear(),
+                            _ => {}
+                        }
+                    } else {
+                        match key.code {
+                            KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                app.open_emoji_picker()
+                            }
+                            KeyCode::Char(c) => app.on_key(c),
+                            KeyCode::Left => app.on_left(),
+                            KeyCode::Up => app.on_up(),
+                            KeyCode::Right => app.on_right(),
+                            KeyCode::Down => app.on_down(),
+                            KeyCode::Esc => app.on_esc(),
+                            KeyCode::Enter => app.on_enter(),
+                            KeyCode::Backspace => app.on_backspace(),
+                            KeyCode::Tab => app.on_tab(),
+                            KeyCode::PageUp => app.on_page_up(),
+                            KeyCode::PageDown => app.on_page_down(),
+                            KeyCode::Home => app.on_home(),
+                            KeyCode::End => app.on_end(),
+                            _ => {}
+                        }
+                    }
                 }
+                Event::Resize(_, _) => app.on_resize(),
+                Event::Mouse(mouse) => {
+                    app.on_activity();
+                    if !app.locked {
+                        app.on_mouse(mouse);
+                    }
+                }
+                _ => {}
             }
         }
         if last_tick.elapsed() >= tick_rate {
@@ -88,3 +166,31 @@ fn run_app<B: Backend>(
         }
     }
 }
+
+/// 在终端中读取一行口令，输入内容不回显
+pub fn read_passphrase(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    enable_raw_mode()?;
+    let mut passphrase = String::new();
+    let res = loop {
+        match event::read() {
+            Ok(Event::Key(key)) => match key.code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"))
+                }
+                KeyCode::Char(c) => passphrase.push(c),
+                KeyCode::Backspace => {
+                    passphrase.pop();
+                }
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    disable_raw_mode()?;
+    println!();
+    res.map(|_| passphrase)
+}
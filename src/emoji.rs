@@ -0,0 +1,255 @@
+use lazy_static::lazy_static;
+use std::{borrow::Cow, collections::HashMap};
+
+/// 微信表情：(方括号中的名字, Unicode 表情, 短代码, 不使用 Unicode 时的字符画)
+/// 字符画为空时保留原来的 `[名字]`
+pub const EMOJIS: [(&str, &str, &str, &str); 90] = [
+    ("微笑", "🙂", "smile", ":)"),
+    ("撇嘴", "😟", "pout", ""),
+    ("色", "😍", "heart_eyes", ""),
+    ("发呆", "😳", "stare", ""),
+    ("得意", "😎", "cool", "B)"),
+    ("流泪", "😢", "tears", ":'("),
+    ("害羞", "😚", "shy", ""),
+    ("闭嘴", "🤐", "zip", ""),
+    ("睡", "😴", "sleep", ""),
+    ("大哭", "😭", "sob", ""),
+    ("尴尬", "😅", "awkward", ""),
+    ("发怒", "😡", "angry", ">:("),
+    ("调皮", "😜", "tongue", ":P"),
+    ("呲牙", "😁", "grin", ":D"),
+    ("惊讶", "😮", "surprised", ":O"),
+    ("难过", "🙁", "sad", ":("),
+    ("囧", "😖", "jiong", ""),
+    ("抓狂", "😫", "crazy", ""),
+    ("吐", "🤮", "vomit", ""),
+    ("偷笑", "🤭", "titter", ""),
+    ("愉快", "😊", "happy", ""),
+    ("白眼", "🙄", "eyeroll", ""),
+    ("傲慢", "😤", "proud", ""),
+    ("困", "😪", "sleepy", ""),
+    ("惊恐", "😱", "scream", ""),
+    ("憨笑", "😄", "laugh", ""),
+    ("悠闲", "😌", "relaxed", ""),
+    ("咒骂", "🤬", "curse", ""),
+    ("疑问", "❓", "question", "?"),
+    ("嘘", "🤫", "shush", ""),
+    ("晕", "😵", "dizzy", ""),
+    ("衰", "😩", "unlucky", ""),
+    ("骷髅", "💀", "skull", ""),
+    ("敲打", "🔨", "hammer", ""),
+    ("再见", "👋", "bye", ""),
+    ("擦汗", "😓", "sweat", ""),
+    ("抠鼻", "👃", "pick_nose", ""),
+    ("鼓掌", "👏", "clap", ""),
+    ("坏笑", "😏", "smirk", ""),
+    ("鄙视", "😒", "despise", ""),
+    ("委屈", "🥺", "wronged", ""),
+    ("快哭了", "😿", "tearful", ""),
+    ("亲亲", "😘", "kiss", ":*"),
+    ("可怜", "😞", "pitiful", ""),
+    ("笑脸", "😃", "smiley", ""),
+    ("生病", "😷", "sick", ""),
+    ("破涕为笑", "😂", "joy", ""),
+    ("恐惧", "😨", "fear", ""),
+    ("失望", "😔", "disappointed", ""),
+    ("无语", "😑", "speechless", ""),
+    ("嘿哈", "😆", "heyha", ""),
+    ("捂脸", "🤦", "facepalm", ""),
+    ("奸笑", "😼", "sneer", ""),
+    ("机智", "🤓", "smart", ""),
+    ("皱眉", "😣", "frown", ""),
+    ("耶", "🙌", "yeah", ""),
+    ("吃瓜", "🍉", "melon", ""),
+    ("加油", "💪", "fighting", ""),
+    ("天啊", "😲", "omg", ""),
+    ("Emm", "🤔", "emm", ""),
+    ("社会社会", "🤙", "social", ""),
+    ("旺柴", "🐶", "doge", ""),
+    ("好的", "🆗", "okay", ""),
+    ("打脸", "🤕", "slap", ""),
+    ("哇", "🤩", "wow", ""),
+    ("让我看看", "👀", "letmesee", ""),
+    ("裂开", "🥴", "crack", ""),
+    ("玫瑰", "🌹", "rose", "@}->--"),
+    ("凋谢", "🥀", "wilted", ""),
+    ("嘴唇", "💋", "lips", ""),
+    ("爱心", "💖", "heart", "<3"),
+    ("心碎", "💔", "broken_heart", "</3"),
+    ("蛋糕", "🎂", "cake", ""),
+    ("炸弹", "💣", "bomb", ""),
+    ("便便", "💩", "poop", ""),
+    ("月亮", "🌙", "moon", ""),
+    ("太阳", "🌞", "sun", ""),
+    ("拥抱", "🤗", "hug", ""),
+    ("强", "👍", "thumbsup", "+1"),
+    ("弱", "👎", "thumbsdown", "-1"),
+    ("握手", "🤝", "handshake", ""),
+    ("胜利", "🏆", "victory", ""),
+    ("抱拳", "🤜", "salute", ""),
+    ("勾引", "👈", "beckon", ""),
+    ("拳头", "👊", "fist", ""),
+    ("OK", "👌", "ok", ""),
+    ("合十", "🙏", "pray", ""),
+    ("啤酒", "🍺", "beer", ""),
+    ("咖啡", "☕", "coffee", ""),
+    ("红包", "🧧", "red_packet", ""),
+];
+
+lazy_static! {
+    static ref BY_NAME: HashMap<&'static str, usize> = EMOJIS
+        .iter()
+        .enumerate()
+        .map(|(idx, emoji)| (emoji.0, idx))
+        .collect();
+    static ref BY_SHORTCODE: HashMap<&'static str, usize> = EMOJIS
+        .iter()
+        .enumerate()
+        .map(|(idx, emoji)| (emoji.2, idx))
+        .collect();
+}
+
+/// 表情名字最长的字节数，超过的 `[...]` 不用去查表
+const MAX_NAME_LEN: usize = 12;
+
+/// 发送给服务端的微信表情代码，比如 `[微笑]`
+pub fn code(idx: usize) -> String {
+    format!("[{}]", EMOJIS[idx].0)
+}
+
+/// 把文本中的 `[微笑]` 换成 Unicode 表情；`enhanced` 为假时换成字符画，没有字符画的保持原样
+pub fn render(text: &str, enhanced: bool) -> Cow<'_, str> {
+    if !text.contains('[') {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('[') {
+        out.push_str(&rest[..open]);
+        let tail = &rest[open + 1..];
+        let emoji = tail
+            .find(']')
+            .filter(|close| *close <= MAX_NAME_LEN)
+            .and_then(|close| {
+                BY_NAME
+                    .get(&tail[..close])
+                    .map(|idx| (close, &EMOJIS[*idx]))
+            });
+        match emoji {
+            Some((close, (_, unicode, _, ascii))) => {
+                if enhanced {
+                    out.push_str(unicode);
+                } else if !ascii.is_empty() {
+                    out.push_str(ascii);
+                } else {
+                    out.push_str(&rest[open..open + close + 2]);
+                }
+                rest = &tail[close + 1..];
+            }
+            None => {
+                out.push('[');
+                rest = tail;
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// 把输入中完整的 `:smile:` 换成微信表情代码。短代码前面只能是开头、空白或者另一个短代码，
+/// 链接和代码中的 `a:ok:b` 保持原样
+pub fn encode_shortcodes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    // 当前位置是否可以开始一个短代码
+    let mut boundary = true;
+    while let Some(open) = rest.find(':') {
+        let before = &rest[..open];
+        if !before.is_empty() {
+            boundary = before.ends_with(char::is_whitespace);
+        }
+        out.push_str(before);
+        let tail = &rest[open + 1..];
+        let found = tail
+            .find(':')
+            .filter(|_| boundary)
+            .and_then(|close| BY_SHORTCODE.get(&tail[..close]).map(|idx| (close, *idx)));
+        match found {
+            Some((close, idx)) => {
+                out.push_str(&code(idx));
+                rest = &tail[close + 1..];
+                boundary = true;
+            }
+            None => {
+                out.push(':');
+                rest = tail;
+                boundary = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// 按短代码查找表情，前缀匹配的排在前面
+pub fn search(prefix: &str) -> Vec<usize> {
+    let prefix = prefix.to_lowercase();
+    let mut found: Vec<usize> = (0..EMOJIS.len())
+        .filter(|idx| EMOJIS[*idx].2.contains(&prefix))
+        .collect();
+    found.sort_by_key(|idx| !EMOJIS[*idx].2.starts_with(&prefix));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_shortcodes_are_lookup_safe() {
+        for (name, _, shortcode, _) in EMOJIS.iter() {
+            assert!(name.len() <= MAX_NAME_LEN, "{}", name);
+            assert!(!shortcode.contains(':'), "{}", shortcode);
+        }
+        assert_eq!(BY_NAME.len(), EMOJIS.len());
+        assert_eq!(BY_SHORTCODE.len(), EMOJIS.len());
+    }
+
+    #[test]
+    fn render_known_and_unknown_codes() {
+        assert_eq!(render("hi[微笑]", true), "hi🙂");
+        assert_eq!(render("[不存在]", true), "[不存在]");
+        assert_eq!(render("[[微笑]", true), "[🙂");
+        assert_eq!(render("[微笑", true), "[微笑");
+        assert_eq!(render("[]", true), "[]");
+        assert!(matches!(render("plain", true), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn render_without_unicode() {
+        assert_eq!(render("[微笑]", false), ":)");
+        // 没有字符画的表情保留原来的代码
+        assert_eq!(render("[撇嘴]", false), "[撇嘴]");
+    }
+
+    #[test]
+    fn encode_known_and_unknown_shortcodes() {
+        assert_eq!(encode_shortcodes(":smile:"), "[微笑]");
+        assert_eq!(encode_shortcodes(":smile::cool:"), "[微笑][得意]");
+        assert_eq!(encode_shortcodes(":nope: :smile"), ":nope: :smile");
+        assert_eq!(encode_shortcodes("10:30 :smile:"), "10:30 [微笑]");
+        assert_eq!(encode_shortcodes("::smile:"), "::smile:");
+    }
+
+    #[test]
+    fn encode_only_shortcodes_after_whitespace() {
+        assert_eq!(encode_shortcodes("a:ok:b"), "a:ok:b");
+        assert_eq!(
+            encode_shortcodes("http://host:smile:8080/"),
+            "http://host:smile:8080/"
+        );
+        assert_eq!(encode_shortcodes("x = y:smile:"), "x = y:smile:");
+        assert_eq!(encode_shortcodes("hi\t:smile: ok"), "hi\t[微笑] ok");
+        assert_eq!(encode_shortcodes("好的 :ok:"), "好的 [OK]");
+    }
+}
@@ -1,3 +1,4 @@
+mod actions;
 mod app;
 // #[cfg(feature = "crossterm")]
 mod crossterm;
@@ -6,16 +7,22 @@ mod crossterm;
 mod ui;
 
 mod client;
+mod config;
+mod emoji;
+mod notify;
+mod preview;
+mod transport;
 mod utils;
 
 // #[cfg(feature = "crossterm")]
-use crate::crossterm::run;
+use crate::crossterm::{read_passphrase, run};
 // #[cfg(feature = "termion")]
 // use crate::termion::run;
 use argh::FromArgs;
-use std::{error::Error, time::Duration};
+use config::{AccountConfig, Config, TlsConfig};
+use std::{error::Error, fs, path::PathBuf, time::Duration};
 use time::{format_description, UtcOffset};
-use tracing::{dispatcher, Dispatch, Level, info};
+use tracing::{dispatcher, info, warn, Dispatch, Level};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{fmt::time::OffsetTime, FmtSubscriber};
 
@@ -29,24 +36,52 @@ struct Cli {
     #[argh(option, default = "true")]
     enhanced_graphics: bool,
 
-    /// address of server or deamon process , default is 127.0.0.1
+    /// address of server or deamon process , default is 127.0.0.1, use unix:/path for unix domain sockets /path.<port>
     #[argh(option, default = "String::from(\"127.0.0.1\")")]
     target: String,
 
-    /// push notification port, default value is 9021
+    /// push notification port, default value is 9022
     #[argh(option, default = "9022")]
     push_notification_port: u16,
 
-    ///  normal message connection port, default value is 9022
+    ///  normal message connection port, default value is 9021
     #[argh(option, default = "9021")]
     normal_message_port: u16,
 
+    ///  port of the single multiplexed connection, pushes and requests share it when set
+    #[argh(option)]
+    port: Option<u16>,
+
+    ///  connect to the server over tls
+    #[argh(switch)]
+    tls: bool,
+
+    ///  pem file of the ca used to verify the server certificate, implies --tls
+    #[argh(option)]
+    tls_ca: Option<String>,
+
+    ///  sha256 fingerprint of the pinned server certificate, implies --tls
+    #[argh(option)]
+    tls_pin: Option<String>,
+
+    ///  file containing the pre-shared token sent to the server on connect
+    #[argh(option)]
+    token_file: Option<String>,
+
     ///  log file home path
     #[argh(
         option,
         default = "String::from(dirs::home_dir().unwrap().to_str().unwrap())"
     )]
     log_home: String,
+
+    ///  config file path, default is ~/.fishloaf/config.json
+    #[argh(option)]
+    config: Option<String>,
+
+    ///  set the passphrase used to unlock the idle lock screen and exit
+    #[argh(switch)]
+    set_passphrase: bool,
 }
 
 fn make_dispatch(log_file_director: &str) -> (Dispatch, WorkerGuard) {
@@ -83,16 +118,70 @@ fn make_dispatch(log_file_director: &str) -> (Dispatch, WorkerGuard) {
 fn main() -> Result<(), Box<dyn Error>> {
     let cli: Cli = argh::from_env();
     let tick_rate = Duration::from_millis(cli.tick_rate);
+    let mut config = Config::load(
+        cli.config
+            .map(PathBuf::from)
+            .unwrap_or_else(Config::default_path),
+    );
 
-    let (dispatcher, guard) = make_dispatch(&format!("{}/{}", &cli.log_home, "fishloaf"));
+    if cli.set_passphrase {
+        let passphrase = read_passphrase("new passphrase: ")?;
+        if passphrase.is_empty() {
+            config.lock_passphrase_hash = None;
+        } else {
+            config.lock_passphrase_hash = Some(config::hash_passphrase(&passphrase));
+        }
+        config.save()?;
+        println!("passphrase saved to {}", config.path.display());
+        return Ok(());
+    }
+
+    if !config.account_errors.is_empty() {
+        return Err(config.account_errors.join("\n").into());
+    }
+
+    // 配置文件中没有账号时使用命令行参数指定的服务端
+    let accounts = if config.accounts.is_empty() {
+        if cli.tls_ca.is_some() && cli.tls_pin.is_some() {
+            return Err("--tls-ca and --tls-pin cannot be used together".into());
+        }
+        let tls = if cli.tls || cli.tls_ca.is_some() || cli.tls_pin.is_some() {
+            Some(TlsConfig {
+                ca_file: cli.tls_ca.map(PathBuf::from),
+                pin_sha256: cli.tls_pin.as_deref().map(config::normalize_fingerprint),
+                server_name: None,
+            })
+        } else {
+            None
+        };
+        // 令牌从文件读取，避免出现在进程列表中
+        let token = match &cli.token_file {
+            Some(path) => Some(fs::read_to_string(path)?.trim().to_owned()),
+            None => None,
+        };
+        vec![AccountConfig {
+            name: String::from("default"),
+            target: cli.target.clone(),
+            push_port: cli.push_notification_port,
+            message_port: cli.normal_message_port,
+            port: cli.port,
+            tls,
+            token,
+        }]
+    } else {
+        config.accounts.clone()
+    };
+
+    let (dispatcher, _guard) = make_dispatch(&format!("{}/{}", &cli.log_home, "fishloaf"));
     dispatcher::with_default(&dispatcher, || {
         info!("fishloaf termchat start!");
-        run(
-            tick_rate,
-            cli.enhanced_graphics,
-            &cli.target,
-            (cli.push_notification_port, cli.normal_message_port),
-        )
+        for account in accounts.iter().filter(|a| a.token_in_cleartext()) {
+            warn!(
+                "account {} sends its token to {} without tls",
+                account.name, account.target
+            );
+        }
+        run(tick_rate, cli.enhanced_graphics, accounts, config)
     })?;
 
     Ok(())
@@ -0,0 +1,53 @@
+use crossterm::{execute, terminal::SetTitle};
+use std::io::{self, Write};
+use tracing::error;
+
+use crate::config::{DesktopNotify, NotifyConfig};
+
+/// 通过终端本身发出新消息通知：响铃以及 OSC 9/777 桌面通知
+pub fn notify_message(config: &NotifyConfig, title: &str, body: &str) {
+    let mut out = String::new();
+    if config.bell {
+        out.push('\x07');
+    }
+    let title = sanitize(title);
+    let body = sanitize(body);
+    match config.desktop {
+        DesktopNotify::Off => {}
+        DesktopNotify::Osc9 => out.push_str(&format!("\x1b]9;{}: {}\x07", title, body)),
+        DesktopNotify::Osc777 => out.push_str(&format!("\x1b]777;notify;{};{}\x07", title, body)),
+    }
+    if out.is_empty() {
+        return;
+    }
+    let mut stdout = io::stdout();
+    if let Err(e) = stdout
+        .write_all(out.as_bytes())
+        .and_then(|_| stdout.flush())
+    {
+        error!("write notification error: {}", e);
+    }
+}
+
+/// 在终端窗口标题中显示未读消息总数
+pub fn update_title(unread: u32) {
+    let title = if unread > 0 {
+        format!("({}) fishloaf", unread)
+    } else {
+        String::from("fishloaf")
+    };
+    if let Err(e) = execute!(io::stdout(), SetTitle(title)) {
+        error!("set terminal title error: {}", e);
+    }
+}
+
+/// 去掉控制字符，避免消息内容中的转义序列被终端执行；`;` 是 OSC 777 的分隔符也一并替换
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            ';' => ',',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect()
+}
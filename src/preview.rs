@@ -0,0 +1,259 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{imageops::FilterType, Rgb, RgbImage};
+use std::{env, fmt::Write, path::Path};
+use tui::{
+    style::{Color, Style},
+    text::{Span, Spans},
+};
+
+use crate::config::ImageProtocol;
+
+/// 解码后保留的最大边长，全屏查看时再按窗口大小缩小
+const MAX_SIDE: u32 = 1600;
+/// sixel 没办法获取字符的像素大小，按常见的 10x20 估算
+const CELL_PIXELS: (u32, u32) = (10, 20);
+
+/// 解码并缩小图片，在后台线程中调用
+pub fn load(path: &Path) -> Result<RgbImage, String> {
+    let image = image::open(path).map_err(|e| e.to_string())?;
+    let image = if image.width() > MAX_SIDE || image.height() > MAX_SIDE {
+        image.resize(MAX_SIDE, MAX_SIDE, FilterType::Triangle)
+    } else {
+        image
+    };
+    Ok(image.to_rgb8())
+}
+
+/// 图片在 `max_cols` x `max_rows` 个字符内保持比例时占用的列数和行数，每行显示两个像素
+pub fn fit(image: &RgbImage, max_cols: u16, max_rows: u16) -> (u16, u16) {
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let mut cols = (max_cols as u32).min(width).max(1);
+    let mut rows = ((cols * height + width) / (2 * width)).max(1);
+    if rows > max_rows as u32 {
+        rows = (max_rows as u32).max(1);
+        cols = (rows * 2 * width / height).clamp(1, max_cols.max(1) as u32);
+    }
+    (cols as u16, rows as u16)
+}
+
+/// 用半块字符 `▀` 渲染图片：前景色是上半格的像素，背景色是下半格的像素
+pub fn half_block_lines(image: &RgbImage, max_cols: u16, max_rows: u16) -> Vec<Spans<'static>> {
+    let (cols, rows) = fit(image, max_cols, max_rows);
+    let small = image::imageops::resize(image, cols as u32, rows as u32 * 2, FilterType::Triangle);
+    let color = |p: &Rgb<u8>| Color::Rgb(p[0], p[1], p[2]);
+    (0..rows as u32)
+        .map(|row| {
+            let spans: Vec<Span> = (0..cols as u32)
+                .map(|x| {
+                    let top = small.get_pixel(x, row * 2);
+                    let bottom = small.get_pixel(x, row * 2 + 1);
+                    Span::styled("▀", Style::default().fg(color(top)).bg(color(bottom)))
+                })
+                .collect();
+            Spans::from(spans)
+        })
+        .collect()
+}
+
+/// 解析配置中的图形协议，`Auto` 时根据终端设置的环境变量判断
+pub fn detect(protocol: ImageProtocol) -> ImageProtocol {
+    if protocol != ImageProtocol::Auto {
+        return protocol;
+    }
+    let term = env::var("TERM").unwrap_or_default();
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+    if env::var_os("KITTY_WINDOW_ID").is_some()
+        || term == "xterm-kitty"
+        || term == "xterm-ghostty"
+        || program == "WezTerm"
+    {
+        ImageProtocol::Kitty
+    } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+        ImageProtocol::Sixel
+    } else {
+        ImageProtocol::HalfBlock
+    }
+}
+
+/// kitty 图形协议：直接传 RGB 数据，由终端缩放到 `cols` x `rows` 个字符
+pub fn kitty_sequence(image: &RgbImage, cols: u16, rows: u16) -> String {
+    let encoded = STANDARD.encode(image.as_raw());
+    let mut out = String::with_capacity(encoded.len() + 1024);
+    // 每段数据最多 4096 字节，m=1 表示后面还有
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let more = if idx + 1 < chunks.len() { 1 } else { 0 };
+        if idx == 0 {
+            let _ = write!(
+                out,
+                "\x1b_Ga=T,f=24,q=2,s={},v={},c={},r={},m={};",
+                image.width(),
+                image.height(),
+                cols,
+                rows,
+                more
+            );
+        } else {
+            let _ = write!(out, "\x1b_Gm={};", more);
+        }
+        out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+        out.push_str("\x1b\\");
+    }
+    out
+}
+
+/// 删除 kitty 终端中显示的所有图片
+pub fn kitty_clear() -> &'static str {
+    "\x1b_Ga=d,q=2\x1b\\"
+}
+
+/// sixel 图形：先把颜色量化到 6x6x6 的调色板，再按每 6 行一个条带输出
+pub fn sixel_sequence(image: &RgbImage, cols: u16, rows: u16) -> String {
+    let image = image::imageops::resize(
+        image,
+        cols as u32 * CELL_PIXELS.0,
+        rows as u32 * CELL_PIXELS.1,
+        FilterType::Triangle,
+    );
+    let (width, height) = image.dimensions();
+    let level = |v: u8| (v as usize * 5 + 127) / 255;
+    let index = |p: &Rgb<u8>| level(p[0]) * 36 + level(p[1]) * 6 + level(p[2]);
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for i in 0..216 {
+        let _ = write!(
+            out,
+            "#{};2;{};{};{}",
+            i,
+            i / 36 * 20,
+            i / 6 % 6 * 20,
+            i % 6 * 20
+        );
+    }
+    let mut band = vec![0u8; width as usize];
+    for top in (0..height).step_by(6) {
+        let bottom = (top + 6).min(height);
+        let mut used = [false; 216];
+        for y in top..bottom {
+            for x in 0..width {
+                used[index(image.get_pixel(x, y))] = true;
+            }
+        }
+        for color in (0..216).filter(|c| used[*c]) {
+            for (x, bits) in band.iter_mut().enumerate() {
+                *bits = 0;
+                for y in top..bottom {
+                    if index(image.get_pixel(x as u32, y)) == color {
+                        *bits |= 1 << (y - top);
+                    }
+                }
+            }
+            let _ = write!(out, "#{}", color);
+            push_sixel_run(&mut out, &band);
+            // 回到条带开头，继续画下一种颜色
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+/// 输出一个条带中某种颜色的数据，连续相同的字符用 `!n` 压缩
+fn push_sixel_run(out: &mut String, band: &[u8]) {
+    let mut iter = band.iter().peekable();
+    while let Some(bits) = iter.next() {
+        let mut count = 1;
+        while iter.peek() == Some(&bits) {
+            iter.next();
+            count += 1;
+        }
+        let c = (63 + bits) as char;
+        if count > 3 {
+            let _ = write!(out, "!{}{}", count, c);
+        } else {
+            for _ in 0..count {
+                out.push(c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, color: [u8; 3]) -> RgbImage {
+        RgbImage::from_pixel(width, height, Rgb(color))
+    }
+
+    #[test]
+    fn fit_keeps_aspect_ratio_within_limits() {
+        // 每行两个像素，正方形图片的行数是列数的一半
+        assert_eq!(fit(&solid(100, 100, [0; 3]), 40, 40), (40, 20));
+        // 太高时按行数限制，再按比例缩小列数
+        assert_eq!(fit(&solid(100, 400, [0; 3]), 40, 10), (5, 10));
+        // 小图片不放大
+        assert_eq!(fit(&solid(4, 4, [0; 3]), 40, 40), (4, 2));
+        assert_eq!(fit(&solid(100, 100, [0; 3]), 0, 0), (1, 1));
+    }
+
+    #[test]
+    fn half_block_lines_match_fit() {
+        let image = solid(100, 50, [255, 0, 0]);
+        let lines = half_block_lines(&image, 20, 10);
+        let (cols, rows) = fit(&image, 20, 10);
+        assert_eq!(lines.len(), rows as usize);
+        for line in &lines {
+            assert_eq!(line.0.len(), cols as usize);
+            for span in &line.0 {
+                assert_eq!(span.content, "▀");
+                assert_eq!(span.style.fg, Some(Color::Rgb(255, 0, 0)));
+                assert_eq!(span.style.bg, Some(Color::Rgb(255, 0, 0)));
+            }
+        }
+    }
+
+    #[test]
+    fn kitty_sequence_is_chunked() {
+        let image = solid(64, 64, [1, 2, 3]);
+        let out = kitty_sequence(&image, 8, 4);
+        assert!(out.starts_with("\x1b_Ga=T,f=24,q=2,s=64,v=64,c=8,r=4,m=1;"));
+        let chunks: Vec<&str> = out.split_terminator("\x1b\\").collect();
+        // 64x64x3 字节的 base64 有 16384 字节，分成 4 段
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks[1..3].iter().all(|c| c.starts_with("\x1b_Gm=1;")));
+        assert!(chunks[3].starts_with("\x1b_Gm=0;"));
+        let payload: String = chunks
+            .iter()
+            .map(|c| &c[c.find(';').unwrap() + 1..])
+            .collect();
+        assert_eq!(STANDARD.decode(payload).unwrap(), image.as_raw().clone());
+    }
+
+    #[test]
+    fn small_kitty_sequence_is_one_chunk() {
+        let out = kitty_sequence(&solid(2, 2, [0; 3]), 1, 1);
+        assert!(out.contains(",m=0;"));
+        assert_eq!(out.matches("\x1b\\").count(), 1);
+    }
+
+    #[test]
+    fn sixel_sequence_shape() {
+        let out = sixel_sequence(&solid(30, 30, [255, 255, 255]), 2, 1);
+        let (width, height) = (2 * CELL_PIXELS.0, CELL_PIXELS.1);
+        assert!(out.starts_with(&format!("\x1bPq\"1;1;{};{}", width, height)));
+        assert!(out.ends_with("\x1b\\"));
+        // 每 6 行一个条带，单色图片每个条带只输出白色
+        let bands = (0..height).step_by(6).count();
+        assert_eq!(out.matches('-').count(), bands);
+        assert_eq!(out.matches("#215!").count(), bands);
+    }
+
+    #[test]
+    fn sixel_runs_are_compressed() {
+        let mut out = String::new();
+        push_sixel_run(&mut out, &[1, 1, 1, 1, 1, 2, 2, 0]);
+        assert_eq!(out, "!5@AA?");
+    }
+}
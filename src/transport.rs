@@ -0,0 +1,233 @@
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, CertificateError, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore,
+    ServerName, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::info;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::config::TlsConfig;
+
+/// Unix domain socket 地址的前缀，例如 `unix:/run/user/1000/fishloaf`
+const UNIX_PREFIX: &str = "unix:";
+
+/// 与 fishloafServer 之间的连接，TCP、TLS 和 Unix domain socket 使用相同的帧格式
+pub enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// 连接服务端。地址为 `unix:/path` 时连接 `/path.<port>`，推送和请求两个通道各用一个 socket 文件，
+/// 这样服务端可以用文件权限限制访问；TCP 连接在设置了 `tls` 时完成 TLS 握手后再返回
+pub fn connect(address: &str, port: u16, tls: Option<&TlsConfig>) -> io::Result<Connection> {
+    if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+        if tls.is_some() {
+            info!("tls is ignored for unix domain socket {}", path);
+        }
+        return connect_unix(&format!("{}.{}", path, port));
+    }
+    let stream = TcpStream::connect((address, port))?;
+    match tls {
+        Some(tls) => connect_tls(stream, address, tls),
+        None => Ok(Connection::Tcp(stream)),
+    }
+}
+
+fn connect_tls(stream: TcpStream, address: &str, tls: &TlsConfig) -> io::Result<Connection> {
+    let name = tls.server_name.as_deref().unwrap_or(address);
+    let server_name =
+        ServerName::try_from(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let connection =
+        ClientConnection::new(client_config(tls)?, server_name).map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(connection, stream);
+    // 先完成握手，证书校验失败时在连接阶段就报错
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    Ok(Connection::Tls(Box::new(stream)))
+}
+
+/// 是否是 Unix domain socket 地址，这类连接不经过网络
+pub fn is_unix(address: &str) -> bool {
+    address.starts_with(UNIX_PREFIX)
+}
+
+fn client_config(tls: &TlsConfig) -> io::Result<Arc<ClientConfig>> {
+    // 同时设置时无法确定该信任哪一个，不要悄悄忽略其中之一
+    if tls.ca_file.is_some() && tls.pin_sha256.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "caFile and pinSha256 cannot be used together",
+        ));
+    }
+    let builder = ClientConfig::builder().with_safe_defaults();
+    if let Some(fingerprint) = &tls.pin_sha256 {
+        let verifier = PinnedCertVerifier {
+            fingerprint: fingerprint.clone(),
+        };
+        return Ok(Arc::new(
+            builder
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth(),
+        ));
+    }
+    let mut roots = RootCertStore::empty();
+    match &tls.ca_file {
+        Some(path) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            for cert in rustls_pemfile::certs(&mut reader)? {
+                roots
+                    .add(&Certificate(cert))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    Ok(Arc::new(
+        builder.with_root_certificates(roots).with_no_client_auth(),
+    ))
+}
+
+/// 固定证书：只比较服务端证书的 sha256 指纹，适用于自签名证书
+struct PinnedCertVerifier {
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual: String = Sha256::digest(&end_entity.0)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if actual == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            let reason = format!("certificate fingerprint mismatch: {}", actual);
+            Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                Arc::from(Box::<dyn std::error::Error + Send + Sync>::from(reason)),
+            )))
+        }
+    }
+}
+
+impl Connection {
+    /// 设置读写超时，超时后读写返回 `WouldBlock` 或 `TimedOut`
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Connection::Tls(stream) => {
+                stream.sock.set_read_timeout(timeout)?;
+                stream.sock.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    /// 只设置读超时，写入大的分片时仍然使用 `set_timeout` 设置的写超时
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            Connection::Tls(stream) => stream.sock.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// 重试也无法解决的连接错误：令牌被拒绝、配置有误或者服务端证书校验失败
+pub fn is_fatal(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput => true,
+        _ => matches!(
+            error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<rustls::Error>()),
+            Some(rustls::Error::InvalidCertificate(_))
+        ),
+    }
+}
+
+/// 读写是否因为超时而返回
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<Connection> {
+    UnixStream::connect(path).map(Connection::Unix)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<Connection> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix domain socket is not supported on this platform",
+    ))
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
@@ -1,21 +1,42 @@
-use crate::app::{App, InputMode};
+use crate::app::{App, DeliveryState, InputMode, LocationView, Message, Popup};
+use crate::client::{format_coordinates, LoginStatus, MessageKind, QuoteRef, PROTOCOL_VERSION};
+use crate::config::{Config, ConversationLayout, ImageProtocol};
+use crate::emoji;
+use crate::preview;
+use image::RgbImage;
+use qrcode::QrCode;
+use std::collections::HashMap;
 use tui::{
     backend::Backend,
-    layout::{Constraint, Direction, Layout, Rect},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     symbols,
     text::{Span, Spans},
     widgets::canvas::{Canvas, Line, Map, MapResolution, Rectangle},
     widgets::{
-        Axis, BarChart, Block, Borders, Cell, Chart, Dataset, Gauge, LineGauge, List, ListItem,
-        Paragraph, Row, Sparkline, Table, Tabs, Wrap,
+        Axis, BarChart, Block, Borders, Cell, Chart, Clear, Dataset, Gauge, LineGauge, List,
+        ListItem, Paragraph, Row, Sparkline, Table, Tabs, Wrap,
     },
     Frame,
 };
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    if app.locked {
+        draw_lock_screen(f, app, f.size());
+        return;
+    }
+    if app.viewer.is_some() {
+        draw_image_viewer(f, app, f.size());
+        return;
+    }
     draw_choosen_tab(f, app, f.size());
+    if app.popup.is_some() {
+        draw_popup(f, app, f.size());
+    }
+    if app.login_modal {
+        draw_login(f, app, f.size());
+    }
     // 1 => draw_second_tab(f, app, chunks[1]),
     // 2 => draw_third_tab(f, app, chunks[1]),
     // _ => {}
@@ -33,6 +54,351 @@ where
     draw_user_input(f, app, chunks[1]);
 }
 
+fn draw_popup<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
+where
+    B: Backend,
+{
+    if let Some(Popup::Location(view)) = &app.popup {
+        draw_location(f, view, area);
+        return;
+    }
+    let accounts = app.account_summaries();
+    let (title, items, state) = match &mut app.popup {
+        Some(Popup::MessageActions(list)) => (
+            String::from("消息操作"),
+            list.items
+                .iter()
+                .map(|a| ListItem::new(a.label()))
+                .collect::<Vec<_>>(),
+            &mut list.state,
+        ),
+        Some(Popup::Recipients(picker)) => (
+            String::from("选择联系人（空格勾选，回车确认）"),
+            picker
+                .list
+                .items
+                .iter()
+                .enumerate()
+                .map(|(idx, (_, name))| {
+                    let mark = if picker.chosen.contains(&idx) {
+                        "[x] "
+                    } else {
+                        "[ ] "
+                    };
+                    ListItem::new(format!("{}{}", mark, name))
+                })
+                .collect(),
+            &mut picker.list.state,
+        ),
+        Some(Popup::Broadcast(state)) => match &app.broadcast {
+            Some(broadcast) => (
+                format!(
+                    "群发 {}/{}（ESC 关闭，后台继续发送）",
+                    broadcast.finished(),
+                    broadcast.deliveries.len()
+                ),
+                broadcast
+                    .deliveries
+                    .iter()
+                    .map(|d| {
+                        let (text, color) = match &d.state {
+                            DeliveryState::Queued => (String::from("等待发送"), Color::DarkGray),
+                            DeliveryState::Sent(..) => (String::from("发送中"), Color::Yellow),
+                            DeliveryState::Delivered => (String::from("成功"), Color::Green),
+                            DeliveryState::Failed(e) => (format!("失败 {}", e), Color::Red),
+                        };
+                        ListItem::new(Spans::from(vec![
+                            Span::raw(format!("{} ", d.display_name)),
+                            Span::styled(text, Style::default().fg(color)),
+                        ]))
+                    })
+                    .collect(),
+                state,
+            ),
+            None => return,
+        },
+        Some(Popup::OpenUrl(list)) => (
+            String::from("打开链接"),
+            list.items
+                .iter()
+                .map(|url| ListItem::new(url.as_str()))
+                .collect(),
+            &mut list.state,
+        ),
+        Some(Popup::Emoji(list)) => (
+            String::from("表情（回车插入，ESC 关闭）"),
+            list.items
+                .iter()
+                .map(|idx| {
+                    let (name, unicode, shortcode, _) = emoji::EMOJIS[*idx];
+                    ListItem::new(format!("{} [{}] :{}:", unicode, name, shortcode))
+                })
+                .collect(),
+            &mut list.state,
+        ),
+        Some(Popup::Accounts(list)) => (
+            String::from("切换账号"),
+            list.items
+                .iter()
+                .map(|idx| {
+                    let (name, unread) = &accounts[*idx];
+                    let text = if *unread > 0 {
+                        format!("{} {} ({})", idx + 1, name, unread)
+                    } else {
+                        format!("{} {}", idx + 1, name)
+                    };
+                    ListItem::new(text)
+                })
+                .collect(),
+            &mut list.state,
+        ),
+        Some(Popup::Unread(list)) => (
+            String::from("未读消息（回车打开）"),
+            list.items
+                .iter()
+                .map(|entry| {
+                    ListItem::new(Spans::from(vec![
+                        Span::styled(
+                            format!("[{}] ", entry.account_name),
+                            Style::default().fg(Color::Blue),
+                        ),
+                        Span::raw(format!("{} ({})", entry.display_name, entry.count)),
+                    ]))
+                })
+                .collect(),
+            &mut list.state,
+        ),
+        Some(Popup::Location(_)) | None => return,
+    };
+    let height = (items.len() as u16 + 2).min(area.height);
+    let area = centered_rect(area, 50, height);
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol("> ");
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(list, area, state);
+}
+
+/// 登录弹窗：未登录时显示登录二维码，登录后显示账号信息
+fn draw_login<B>(f: &mut Frame<B>, app: &App, area: Rect)
+where
+    B: Backend,
+{
+    let mut lines: Vec<Spans> = Vec::new();
+    let title = match &app.login {
+        Some(LoginStatus {
+            logged_in: true,
+            account,
+            ..
+        }) => {
+            if let Some(account) = account {
+                let mut rows = vec![
+                    ("昵称", Some(account.display_name.clone())),
+                    ("微信号", account.alias.clone()),
+                    ("id", Some(account.unique_id.clone())),
+                    ("地区", account.region.clone()),
+                    ("签名", account.signature.clone()),
+                ];
+                rows.retain(|(_, value)| value.is_some());
+                for (name, value) in rows {
+                    lines.push(Spans::from(vec![
+                        Span::styled(format!("{:　<4}", name), Style::default().fg(Color::Blue)),
+                        Span::raw(value.unwrap_or_default()),
+                    ]));
+                }
+            }
+            "已登录（任意键关闭）"
+        }
+        Some(LoginStatus {
+            qrcode: Some(qrcode),
+            ..
+        }) => {
+            match qr_lines(qrcode) {
+                Ok(qr) => lines.extend(qr),
+                Err(e) => lines.push(Spans::from(format!("二维码生成失败: {}", e))),
+            }
+            lines.push(Spans::from(""));
+            lines.push(Spans::from("请使用微信扫描二维码登录"));
+            "登录（ESC 关闭，A 键重新打开）"
+        }
+        _ => {
+            lines.push(Spans::from("正在获取登录二维码…"));
+            "登录（ESC 关闭，A 键重新打开）"
+        }
+    };
+    let width = lines
+        .iter()
+        .map(|line| line.width())
+        .chain(std::iter::once(title.width()))
+        .max()
+        .unwrap_or(0) as u16
+        + 4;
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let width = width.min(area.width);
+    let rect = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+    let paragraph = Paragraph::new(lines)
+        .alignment(Alignment::Center)
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(Clear, rect);
+    f.render_widget(paragraph, rect);
+}
+
+/// 用半块字符画出二维码，每个字符上下两个模块，四周留两个模块的空白
+fn qr_lines(payload: &str) -> Result<Vec<Spans<'static>>, String> {
+    let code = QrCode::new(payload.as_bytes()).map_err(|e| e.to_string())?;
+    let width = code.width();
+    let quiet = 2;
+    let size = width + quiet * 2;
+    let dark = |x: usize, y: usize| {
+        x >= quiet
+            && y >= quiet
+            && x < width + quiet
+            && y < width + quiet
+            && code[(x - quiet, y - quiet)] == qrcode::Color::Dark
+    };
+    let color = |dark: bool| if dark { Color::Black } else { Color::White };
+    Ok((0..size)
+        .step_by(2)
+        .map(|y| {
+            let spans: Vec<Span> = (0..size)
+                .map(|x| {
+                    Span::styled(
+                        "▀",
+                        Style::default()
+                            .fg(color(dark(x, y)))
+                            .bg(color(dark(x, y + 1))),
+                    )
+                })
+                .collect();
+            Spans::from(spans)
+        })
+        .collect())
+}
+
+/// 位置消息的地图弹窗，位置所在处用红点标出
+fn draw_location<B>(f: &mut Frame<B>, view: &LocationView, area: Rect)
+where
+    B: Backend,
+{
+    let area = centered_rect(area, 80, area.height * 4 / 5);
+    let (x_bounds, y_bounds) = view.bounds();
+    let title = format!(
+        "{} {}（上下键或 +/- 缩放，ESC 关闭）",
+        view.label,
+        format_coordinates(view.latitude, view.longitude)
+    );
+    let map = Canvas::default()
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .marker(symbols::Marker::Braille)
+        .x_bounds(x_bounds)
+        .y_bounds(y_bounds)
+        .paint(|ctx| {
+            ctx.draw(&Map {
+                color: Color::Gray,
+                resolution: MapResolution::High,
+            });
+            ctx.layer();
+            ctx.print(
+                view.longitude,
+                view.latitude,
+                Span::styled(
+                    "●",
+                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                ),
+            );
+        });
+    f.render_widget(Clear, area);
+    f.render_widget(map, area);
+}
+
+/// 全屏查看图片：半块字符直接画在缓冲区中，kitty/sixel 只记录位置，由外层在绘制后输出
+fn draw_image_viewer<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
+where
+    B: Backend,
+{
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("查看图片（任意键关闭）");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let viewer = match &mut app.viewer {
+        Some(viewer) => viewer,
+        None => return,
+    };
+    let image = match app.previews.get(&viewer.attachment_id) {
+        Some(image) => image,
+        None => return,
+    };
+    let (cols, rows) = preview::fit(image, inner.width, inner.height);
+    let rect = Rect {
+        x: inner.x + inner.width.saturating_sub(cols) / 2,
+        y: inner.y + inner.height.saturating_sub(rows) / 2,
+        width: cols.min(inner.width),
+        height: rows.min(inner.height),
+    };
+    if app.image_protocol == ImageProtocol::HalfBlock {
+        let lines = preview::half_block_lines(image, cols, rows);
+        f.render_widget(Paragraph::new(lines), rect);
+    } else if viewer.area != Some(rect) {
+        viewer.area = Some(rect);
+        viewer.drawn = false;
+    }
+}
+
+/// 在 `area` 中间取出宽度占 `percent_x`%、高度为 `height` 行的区域
+fn centered_rect(area: Rect, percent_x: u16, height: u16) -> Rect {
+    let width = area.width * percent_x / 100;
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height.saturating_sub(height)) / 2,
+        width,
+        height: height.min(area.height),
+    }
+}
+
+fn draw_lock_screen<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
+where
+    B: Backend,
+{
+    let chunks = Layout::default()
+        .constraints(
+            [
+                Constraint::Percentage(40),
+                Constraint::Length(6),
+                Constraint::Min(0),
+            ]
+            .as_ref(),
+        )
+        .split(area);
+    let mut text = vec![
+        Spans::from(Span::styled(
+            "fishloaf locked",
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Spans::from(format!("{} new message(s)", app.locked_received)),
+        Spans::from(format!(
+            "passphrase: {}",
+            "*".repeat(app.lock_input.chars().count())
+        )),
+    ];
+    if app.lock_error {
+        text.push(Spans::from(Span::styled(
+            "wrong passphrase",
+            Style::default().fg(Color::Red),
+        )));
+    }
+    let paragraph = Paragraph::new(text)
+        .alignment(Alignment::Center)
+        .block(Block::default().borders(Borders::TOP | Borders::BOTTOM));
+    f.render_widget(paragraph, chunks[1]);
+}
+
 fn draw_user_input<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
 where
     B: Backend,
@@ -42,7 +408,12 @@ where
             InputMode::Normal => Style::default(),
             InputMode::Editing => Style::default().fg(Color::Yellow),
         })
-        .block(Block::default().borders(Borders::TOP).title("Input"));
+        .block(
+            Block::default()
+                .borders(Borders::TOP)
+                .title(input_title(app)),
+        );
+    app.areas.input = area;
     f.render_widget(input, area);
     match app.input_mode {
         InputMode::Normal =>
@@ -61,10 +432,117 @@ where
     }
 }
 
+fn input_title(app: &App) -> String {
+    let mut title = String::from("Input");
+    if let Some(quoted) = &app.reply_to {
+        let snippet: String = quoted.message.chars().take(20).collect();
+        title.push_str(&format!(" [回复 {}: {}]", quoted.speaker, snippet));
+    }
+    if let Some(completion) = &app.completion {
+        let names: Vec<&str> = completion
+            .candidates
+            .iter()
+            .map(|m| m.display_name.as_str())
+            .collect();
+        title.push_str(&format!(
+            " [@ {}/{}: {}]",
+            completion.index + 1,
+            names.len(),
+            names.join(" ")
+        ));
+    }
+    if let Some(completion) = &app.emoji_completion {
+        let names: Vec<String> = completion
+            .candidates
+            .iter()
+            .map(|idx| format!(":{}:", emoji::EMOJIS[*idx].2))
+            .collect();
+        title.push_str(&format!(
+            " [{}/{}: {}]",
+            completion.index + 1,
+            names.len(),
+            names.join(" ")
+        ));
+    }
+    if let Some(broadcast) = &app.broadcast {
+        let (finished, total) = (broadcast.finished(), broadcast.deliveries.len());
+        if finished < total {
+            title.push_str(&format!(" [群发 {}/{}]", finished, total));
+        }
+    }
+    if let Some(targets) = &app.broadcast_targets {
+        title.push_str(&format!(" [群发给 {} 个联系人]", targets.len()));
+    }
+    if let Some((status, _)) = &app.status {
+        title.push_str(&format!(" - {}", status));
+    }
+    title
+}
+
+fn groups_title(app: &App) -> String {
+    let mut title = String::from("groups");
+    if app.sessions.len() > 1 {
+        title = format!("[{}] {}", app.account, title);
+    }
+    match &app.login {
+        Some(LoginStatus {
+            logged_in: true,
+            account: Some(account),
+            ..
+        }) => title.push_str(&format!(" - {}", account.display_name)),
+        Some(LoginStatus {
+            logged_in: false, ..
+        }) => title.push_str(" [未登录]"),
+        _ => {}
+    }
+    if let Some(reason) = app.message_callback.state.fatal() {
+        title.push_str(&format!(" [连接失败: {}]", reason));
+    } else if app.message_callback.state.stale() {
+        title.push_str(" [连接中断]");
+    }
+    let protocol_errors = app.message_callback.state.protocol_errors.total();
+    if protocol_errors > 0 {
+        title.push_str(&format!(" [协议错误{}]", protocol_errors));
+    }
+    match &app.server {
+        Some(server) if server.protocol_version == 0 => title.push_str(" [旧版服务端]"),
+        Some(server) if server.protocol_version != PROTOCOL_VERSION => {
+            title.push_str(&format!(" [协议v{}]", server.protocol_version))
+        }
+        _ => {}
+    }
+    if app.config.dnd.active() {
+        title.push_str(" [勿扰]");
+    }
+    // 其他账号的未读数
+    let others: u32 = app
+        .account_summaries()
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| *idx != app.active_session)
+        .map(|(_, (_, unread))| unread)
+        .sum();
+    if others > 0 {
+        title.push_str(&format!(" 其他账号({})", others));
+    }
+    title
+}
+
 fn draw_talk_list<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
 where
     B: Backend,
 {
+    let block = Block::default()
+        .borders(if app.focus == app.groups.mark {
+            Borders::ALL
+        } else {
+            Borders::BOTTOM | Borders::RIGHT
+        })
+        .title(groups_title(app));
+    let inner = block.inner(area);
+    app.areas.groups = area;
+    app.areas.groups_list = inner;
+    app.groups.sync_offset(inner.height as usize);
     let group_name_style = Style::default().fg(Color::Blue);
     let groups: Vec<ListItem> = app
         .groups
@@ -81,44 +559,36 @@ where
                 0u16
             };
             let t = &m.display_name;
-            if m.is_group {
-                if unread_message > 0 {
-                    ListItem::new(vec![Spans::from(vec![
-                        Span::raw(format!("({})", unread_message)),
-                        Span::styled(t.as_ref(), group_name_style),
-                        Span::raw("[群]"),
-                    ])])
-                } else {
-                    ListItem::new(vec![Spans::from(vec![
-                        Span::styled(t.as_ref(), group_name_style),
-                        Span::raw("[群]"),
-                    ])])
-                }
-            } else {
-                if unread_message > 0 {
-                    ListItem::new(vec![Spans::from(vec![
-                        Span::raw(format!("({})", unread_message)),
-                        Span::styled(t.as_ref(), group_name_style),
-                    ])])
+            let muted = app.config.muted.contains(m.unique_id.as_ref());
+            let mut spans = Vec::new();
+            if app.config.pinned.contains(m.unique_id.as_ref()) {
+                spans.push(Span::raw("[顶]"));
+            }
+            if app.message_mentioned.contains(m.unique_id.as_ref()) {
+                spans.push(Span::styled(
+                    "[@]",
+                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                ));
+            }
+            if unread_message > 0 {
+                if muted {
+                    spans.push(Span::raw("(•)"));
                 } else {
-                    ListItem::new(vec![Spans::from(vec![Span::styled(
-                        t.as_ref(),
-                        group_name_style,
-                    )])])
+                    spans.push(Span::raw(format!("({})", unread_message)));
                 }
             }
+            spans.push(Span::styled(t.as_ref(), group_name_style));
+            if m.is_group {
+                spans.push(Span::raw("[群]"));
+            }
+            if muted {
+                spans.push(Span::styled("[免]", Style::default().fg(Color::DarkGray)));
+            }
+            ListItem::new(vec![Spans::from(spans)])
         })
         .collect();
     let groups = List::new(groups)
-        .block(
-            Block::default()
-                .borders(if app.focus == app.groups.mark {
-                    Borders::ALL
-                } else {
-                    Borders::BOTTOM | Borders::RIGHT
-                })
-                .title("groups"),
-        )
+        .block(block)
         .highlight_style(Style::default().add_modifier(Modifier::BOLD))
         .highlight_symbol("> ");
     f.render_stateful_widget(groups, area, &mut app.groups.state);
@@ -128,49 +598,472 @@ fn draw_conversation<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
 where
     B: Backend,
 {
-    let speaker_name_style = Style::default().fg(Color::Blue);
-    // Draw tasks
-    let tasks: Vec<ListItem> = app
-        .tasks
-        .items
-        .iter()
-        .map(|m| {
-            ListItem::new(vec![Spans::from(vec![
-                Span::styled(&m.speaker, speaker_name_style),
-                Span::raw(" >> "),
-                Span::raw(&m.message),
-            ])])
-        })
-        .collect();
     let dialog_name = if let Some(name) = &app.target_display_name {
         name.as_ref()
     } else {
         "dialog"
     };
+    let block = Block::default()
+        .borders(if app.focus == app.tasks.mark {
+            Borders::ALL
+        } else {
+            Borders::BOTTOM | Borders::LEFT | Borders::RIGHT
+        })
+        .title(dialog_name);
+    let inner = block.inner(area);
+    // 左侧留两列给选中标记 "> "
+    let width = inner.width.saturating_sub(2) as usize;
+    let selected = app.tasks.state.selected();
+    let enhanced = app.enhanced_graphics;
+    let mut lines: Vec<Spans> = Vec::new();
+    let mut starts = Vec::with_capacity(app.tasks.items.len());
+    for (idx, m) in app.tasks.items.iter().enumerate() {
+        starts.push(lines.len());
+        match app.config.layout {
+            ConversationLayout::Line => {
+                lines.extend(message_lines(m, width, selected == Some(idx), enhanced))
+            }
+            ConversationLayout::Bubble => {
+                let prev = idx.checked_sub(1).map(|p| &app.tasks.items[p]);
+                lines.extend(bubble_lines(
+                    m,
+                    prev,
+                    width,
+                    selected == Some(idx),
+                    enhanced,
+                ))
+            }
+        }
+        if let Some(lines_of_image) =
+            inline_image_lines(&app.previews, &mut app.preview_lines, &app.config, m, width)
+        {
+            lines.extend(lines_of_image);
+        }
+    }
 
-    let tasks = List::new(tasks)
-        .block(
-            Block::default()
-                .borders(if app.focus == app.tasks.mark {
-                    Borders::ALL
+    let scroll = &mut app.scroll;
+    scroll.total_lines = lines.len();
+    scroll.height = inner.height as usize;
+    if scroll.reveal_selected {
+        scroll.reveal_selected = false;
+        if let Some(idx) = selected.filter(|idx| *idx < starts.len()) {
+            let begin = starts[idx];
+            let end = starts.get(idx + 1).copied().unwrap_or(lines.len());
+            let mut top = scroll.top.unwrap_or_else(|| scroll.max_top());
+            if begin < top {
+                top = begin;
+            } else if end > top + scroll.height {
+                top = end.saturating_sub(scroll.height).min(begin);
+            }
+            scroll.top = Some(top);
+        }
+    }
+    let top = match scroll.top {
+        Some(top) if top < scroll.max_top() => top,
+        _ => {
+            scroll.follow();
+            scroll.max_top()
+        }
+    };
+    let visible: Vec<Spans> = lines
+        .into_iter()
+        .skip(top)
+        .take(inner.height as usize)
+        .collect();
+
+    app.areas.conversation = area;
+    f.render_widget(Paragraph::new(visible).block(block), area);
+
+    if app.scroll.unseen > 0 && inner.height > 0 {
+        let indicator = Paragraph::new(Spans::from(Span::styled(
+            format!(" ↓ {} new message(s) below ", app.scroll.unseen),
+            Style::default().add_modifier(Modifier::REVERSED),
+        )))
+        .alignment(Alignment::Right);
+        f.render_widget(
+            indicator,
+            Rect {
+                y: inner.y + inner.height - 1,
+                height: 1,
+                ..inner
+            },
+        );
+    }
+}
+
+/// 把一条消息渲染成若干行：首行是 `speaker >> text`，正文超出宽度时折行
+fn message_lines(m: &Message, width: usize, selected: bool, enhanced: bool) -> Vec<Spans<'static>> {
+    if let MessageKind::System = m.kind {
+        return system_lines(m, width, selected, enhanced);
+    }
+    let mut speaker_style = Style::default().fg(Color::Blue);
+    let mut text_style = if m.highlight {
+        Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::BOLD)
+    } else {
+        Style::default()
+    };
+    if m.recalled {
+        text_style = recalled_style(text_style);
+    }
+    if selected {
+        speaker_style = speaker_style.add_modifier(Modifier::BOLD);
+        text_style = text_style.add_modifier(Modifier::BOLD);
+    }
+    let gutter = |first: bool| {
+        if first && selected {
+            Span::styled("> ", Style::default().add_modifier(Modifier::BOLD))
+        } else {
+            Span::raw("  ")
+        }
+    };
+
+    let header = format!("{} >> ", m.speaker);
+    let header_width = header.width();
+    let content = m.display_text();
+    let content = emoji::render(&content, enhanced);
+    let mut lines = Vec::new();
+    let text = if header_width + 4 <= width {
+        let mut wrapped = wrap_text(&content, width - header_width, width).into_iter();
+        lines.push(Spans::from(vec![
+            gutter(true),
+            Span::styled(m.speaker.clone(), speaker_style),
+            Span::raw(" >> "),
+            Span::styled(wrapped.next().unwrap_or_default(), text_style),
+        ]));
+        wrapped.collect()
+    } else {
+        // 昵称太长，正文另起一行
+        lines.push(Spans::from(vec![
+            gutter(true),
+            Span::styled(header, speaker_style),
+        ]));
+        wrap_text(&content, width, width)
+    };
+    for line in text {
+        lines.push(Spans::from(vec![
+            gutter(false),
+            Span::styled(line, text_style),
+        ]));
+    }
+    if m.recalled {
+        lines.push(Spans::from(vec![gutter(false), recalled_mark()]));
+    }
+    if let Some(quote) = &m.quote {
+        for line in quote_lines(quote, width.saturating_sub(2), enhanced) {
+            lines.push(Spans::from(vec![gutter(false), Span::raw("  "), line]));
+        }
+    }
+    lines
+}
+
+/// 已经加载好的图片直接显示在消息下方，气泡排版中自己的图片靠右。
+/// 缩放后的结果按尺寸缓存，每次绘制不用重新缩放
+fn inline_image_lines(
+    previews: &HashMap<String, RgbImage>,
+    cache: &mut HashMap<(String, u16, u16), Vec<Spans<'static>>>,
+    config: &Config,
+    m: &Message,
+    width: usize,
+) -> Option<Vec<Spans<'static>>> {
+    if !config.images.inline {
+        return None;
+    }
+    if !matches!(m.kind, MessageKind::Image { .. }) {
+        return None;
+    }
+    let attachment_id = m.attachment_id.as_ref()?;
+    let image = previews.get(attachment_id)?;
+    let max_cols = width.saturating_sub(4).min(u16::MAX as usize) as u16;
+    if max_cols == 0 {
+        return None;
+    }
+    let max_rows = config.images.max_rows;
+    let lines = cache
+        .entry((attachment_id.clone(), max_cols, max_rows))
+        .or_insert_with(|| preview::half_block_lines(image, max_cols, max_rows));
+    let right = m.mine && config.layout == ConversationLayout::Bubble;
+    Some(
+        lines
+            .iter()
+            .cloned()
+            .map(|mut line| {
+                let pad = if right {
+                    width.saturating_sub(line.width())
                 } else {
-                    Borders::BOTTOM | Borders::LEFT | Borders::RIGHT
-                })
-                .title(dialog_name),
-        )
-        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
-        .highlight_symbol("> ");
-    f.render_stateful_widget(tasks, area, &mut app.tasks.state);
+                    4
+                };
+                line.0.insert(0, Span::raw(" ".repeat(pad + 2)));
+                line
+            })
+            .collect(),
+    )
+}
+
+/// 防撤回保留下来的消息以灰色删除线显示
+fn recalled_style(style: Style) -> Style {
+    style
+        .fg(Color::DarkGray)
+        .add_modifier(Modifier::CROSSED_OUT)
+}
+
+fn recalled_mark() -> Span<'static> {
+    Span::styled(
+        "(已撤回)",
+        Style::default()
+            .fg(Color::Red)
+            .add_modifier(Modifier::ITALIC),
+    )
+}
+
+/// 入群、退群、撤回等系统提示居中显示，不带昵称
+fn system_lines(m: &Message, width: usize, selected: bool, enhanced: bool) -> Vec<Spans<'static>> {
+    let mut style = Style::default().fg(Color::DarkGray);
+    if selected {
+        style = style.add_modifier(Modifier::BOLD);
+    }
+    wrap_text(&emoji::render(&m.message, enhanced), width, width)
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let gutter = if i == 0 && selected { "> " } else { "  " };
+            let pad = width.saturating_sub(line.width()) / 2;
+            Spans::from(vec![
+                Span::raw(gutter),
+                Span::raw(" ".repeat(pad)),
+                Span::styled(line, style),
+            ])
+        })
+        .collect()
+}
+
+/// 被引用的消息缩进显示在正文下方，最多两行
+fn quote_lines(quote: &QuoteRef, width: usize, enhanced: bool) -> Vec<Span<'static>> {
+    let style = Style::default().fg(Color::DarkGray);
+    let text = format!(
+        "{}: {}",
+        quote.display_name,
+        emoji::render(&quote.text, enhanced).replace('\n', " ")
+    );
+    let mut wrapped = wrap_text(&text, width.saturating_sub(2), width.saturating_sub(2));
+    if wrapped.len() > 2 {
+        wrapped.truncate(2);
+        wrapped[1].push('…');
+    }
+    wrapped
+        .into_iter()
+        .map(|line| Span::styled(format!("┆ {}", line), style))
+        .collect()
+}
+
+/// 气泡排版：同一个人连续发送的消息只在第一条上方显示一次昵称，自己的消息靠右
+fn bubble_lines(
+    m: &Message,
+    prev: Option<&Message>,
+    width: usize,
+    selected: bool,
+    enhanced: bool,
+) -> Vec<Spans<'static>> {
+    if let MessageKind::System = m.kind {
+        return system_lines(m, width, selected, enhanced);
+    }
+    let mut text_style = if m.highlight {
+        Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::BOLD)
+    } else {
+        Style::default()
+    };
+    if m.recalled {
+        text_style = recalled_style(text_style);
+    }
+    if selected {
+        text_style = text_style.add_modifier(Modifier::BOLD);
+    }
+    let mut lines = Vec::new();
+    let new_group = match prev {
+        Some(p) => {
+            p.mine != m.mine
+                || (!m.mine && p.speaker_id != m.speaker_id)
+                || matches!(p.kind, MessageKind::System)
+        }
+        None => true,
+    };
+    if new_group {
+        if prev.is_some() {
+            lines.push(Spans::default());
+        }
+        let (name, color) = if m.mine {
+            (String::from("我"), Color::Green)
+        } else {
+            (m.speaker.clone(), speaker_color(&m.speaker_id))
+        };
+        let pad = if m.mine {
+            width.saturating_sub(name.width())
+        } else {
+            0
+        };
+        lines.push(Spans::from(vec![
+            Span::raw("  "),
+            Span::raw(" ".repeat(pad)),
+            Span::styled(
+                name,
+                Style::default().fg(color).add_modifier(Modifier::BOLD),
+            ),
+        ]));
+    }
+
+    // 自己的消息最多占四分之三宽度并靠右，别人的消息在昵称下缩进两格
+    let body_width = if m.mine {
+        width * 3 / 4
+    } else {
+        width.saturating_sub(2)
+    }
+    .max(1);
+    for (i, line) in wrap_text(
+        &emoji::render(&m.display_text(), enhanced),
+        body_width,
+        body_width,
+    )
+    .into_iter()
+    .enumerate()
+    {
+        let gutter = if i == 0 && selected {
+            Span::styled("> ", Style::default().add_modifier(Modifier::BOLD))
+        } else {
+            Span::raw("  ")
+        };
+        let pad = if m.mine {
+            width.saturating_sub(line.width())
+        } else {
+            2
+        };
+        lines.push(Spans::from(vec![
+            gutter,
+            Span::raw(" ".repeat(pad)),
+            Span::styled(line, text_style),
+        ]));
+    }
+    if m.recalled {
+        let mark = recalled_mark();
+        let pad = if m.mine {
+            width.saturating_sub(mark.width())
+        } else {
+            2
+        };
+        lines.push(Spans::from(vec![
+            Span::raw("  "),
+            Span::raw(" ".repeat(pad)),
+            mark,
+        ]));
+    }
+    if let Some(quote) = &m.quote {
+        for line in quote_lines(quote, body_width, enhanced) {
+            let pad = if m.mine {
+                width.saturating_sub(line.width())
+            } else {
+                2
+            };
+            lines.push(Spans::from(vec![
+                Span::raw("  "),
+                Span::raw(" ".repeat(pad)),
+                line,
+            ]));
+        }
+    }
+    lines
+}
+
+/// 根据发言人 id 计算一个固定的颜色，同一个人在不同会话、不同启动之间颜色不变
+fn speaker_color(speaker_id: &str) -> Color {
+    const PALETTE: [Color; 10] = [
+        Color::Red,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::LightRed,
+        Color::LightYellow,
+        Color::LightBlue,
+        Color::LightMagenta,
+        Color::LightCyan,
+    ];
+    // FNV-1a
+    let hash = speaker_id.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+fn last_break(line: &str) -> Option<(usize, usize)> {
+    let mut brk = None;
+    let mut line_width = 0;
+    for (idx, c) in line.char_indices() {
+        let char_width = c.width().unwrap_or(0);
+        line_width += char_width;
+        if c.is_whitespace() || char_width > 1 {
+            brk = Some((idx + c.len_utf8(), line_width));
+        }
+    }
+    brk
+}
+
+/// 按显示宽度折行，优先在空白或中日韩字符之后断开，单词超过一行时强制断开。
+/// 第一行可用宽度为 `first_width`，其余各行为 `width`
+fn wrap_text(text: &str, first_width: usize, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0usize;
+        // 最近一个可以断行的位置（字节下标）及其之前的显示宽度
+        let mut brk: Option<(usize, usize)> = None;
+        let mut wrapped = false;
+        for c in paragraph.chars() {
+            let c = match c {
+                '\t' => ' ',
+                '\r' => continue,
+                c => c,
+            };
+            let char_width = c.width().unwrap_or(0);
+            let limit = if lines.is_empty() { first_width } else { width }.max(1);
+            while line_width + char_width > limit && !line.is_empty() {
+                match brk.take() {
+                    Some((idx, brk_width)) if idx < line.len() && !c.is_whitespace() => {
+                        let rest = line.split_off(idx);
+                        lines.push(line.trim_end().to_string());
+                        line = rest;
+                        line_width -= brk_width;
+                        brk = last_break(&line);
+                    }
+                    _ => {
+                        lines.push(std::mem::take(&mut line).trim_end().to_string());
+                        line_width = 0;
+                    }
+                }
+                wrapped = true;
+            }
+            // 折行处的空白不出现在下一行开头，原文的缩进保留
+            if wrapped && line.is_empty() && c.is_whitespace() {
+                continue;
+            }
+            wrapped = false;
+            line.push(c);
+            line_width += char_width;
+            if c.is_whitespace() || char_width > 1 {
+                brk = Some((line.len(), line_width));
+            }
+        }
+        lines.push(line);
+    }
+    lines
 }
 
 fn draw_dialog<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
 where
     B: Backend,
 {
-    let constraints = vec![
-        Constraint::Percentage(20),
-        Constraint::Percentage(80),
-    ];
+    let constraints = vec![Constraint::Percentage(20), Constraint::Percentage(80)];
     let chunks = Layout::default()
         .constraints(constraints)
         .direction(Direction::Horizontal)
@@ -350,3 +1243,47 @@ where
 //         ]);
 //     f.render_widget(table, chunks[0]);
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_at_whitespace() {
+        assert_eq!(
+            wrap_text("hello world foo", 11, 11),
+            vec!["hello world", "foo"]
+        );
+        assert_eq!(wrap_text("hello world", 5, 5), vec!["hello", "world"]);
+    }
+
+    #[test]
+    fn wrap_long_word_is_forced() {
+        assert_eq!(wrap_text("abcdefgh", 3, 3), vec!["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn wrap_wide_char_at_boundary() {
+        // 剩余宽度只有 1 时，双宽字符整体移到下一行
+        assert_eq!(wrap_text("a你好世界", 4, 4), vec!["a你", "好世", "界"]);
+        assert_eq!(wrap_text("你好世界", 5, 5), vec!["你好", "世界"]);
+        for line in wrap_text("混合 mixed 中英文 text 折行测试", 7, 7) {
+            assert!(line.width() <= 7, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn wrap_first_line_narrower() {
+        assert_eq!(wrap_text("aa bb cc dd", 2, 5), vec!["aa", "bb cc", "dd"]);
+    }
+
+    #[test]
+    fn wrap_keeps_paragraphs_and_indent() {
+        assert_eq!(wrap_text("a\n  b\n", 10, 10), vec!["a", "  b", ""]);
+    }
+
+    #[test]
+    fn wrap_zero_width_does_not_loop() {
+        assert_eq!(wrap_text("ab", 0, 0), vec!["a", "b"]);
+    }
+}
@@ -1,19 +1,30 @@
-use std::{io::Read, net::TcpStream, result};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::Utf8Error,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 const MAGIC: [u8; 3] = [0xf1, 0x60, 0x6f];
-fn check_header(head: [u8; 7]) -> result::Result<usize, String> {
-    for i in 0usize..3 {
-        if head[i] != MAGIC[i] {
-            return Err(String::from("header not match"));
-        }
-    }
-    let len_data = &head[3..7];
-    let len = (len_data[0] as usize) << 24
-        | (len_data[1] as usize) << 16
-        | (len_data[2] as usize) << 8
-        | len_data[3] as usize;
+/// 二进制分片帧的魔数，长度字段之后是 2 字节的描述长度、JSON 描述和原始数据
+const MAGIC_CHUNK: [u8; 3] = [0xf1, 0x60, 0x63];
+/// 单连接模式下服务端推送消息的魔数，格式与文本帧相同，用来和请求的响应区分
+const MAGIC_PUSH: [u8; 3] = [0xf1, 0x60, 0x70];
+const FRAME_MAGICS: [[u8; 3]; 3] = [MAGIC, MAGIC_CHUNK, MAGIC_PUSH];
+/// 附件上传时每个分片的大小
+pub const CHUNK_SIZE: usize = 64 * 1024;
 
-    Ok(len)
+/// 一帧数据：普通的 JSON 文本，或者附件上传下载使用的二进制分片
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Text(String),
+    /// 单连接模式下的推送消息
+    Push(String),
+    /// `meta` 为分片描述，包含 traceId、序号 seq 以及是否最后一片 last
+    Chunk {
+        meta: String,
+        data: Vec<u8>,
+    },
 }
 
 pub fn build_message(message: &str) -> Vec<u8> {
@@ -28,38 +39,499 @@ pub fn build_message(message: &str) -> Vec<u8> {
     len_byte[2] = ((len >> 8) & 0xff) as u8;
     len_byte[3] = (len & 0xff) as u8;
     data.extend_from_slice(&len_byte);
-    data.extend_from_slice(&byte_data);
-    return data;
+    data.extend_from_slice(byte_data);
+    data
+}
+
+pub fn build_push(message: &str) -> Vec<u8> {
+    let mut data = build_message(message);
+    data[..3].copy_from_slice(&MAGIC_PUSH);
+    data
+}
+
+pub fn build_chunk(meta: &str, chunk: &[u8]) -> Vec<u8> {
+    let meta = meta.as_bytes();
+    let len = 2 + meta.len() + chunk.len();
+    let mut data: Vec<u8> = Vec::with_capacity(3 + 4 + len);
+    data.extend_from_slice(&MAGIC_CHUNK);
+    data.extend_from_slice(&(len as u32).to_be_bytes());
+    data.extend_from_slice(&(meta.len() as u16).to_be_bytes());
+    data.extend_from_slice(meta);
+    data.extend_from_slice(chunk);
+    data
+}
+
+pub fn build_frame(frame: &Frame) -> Vec<u8> {
+    match frame {
+        Frame::Text(message) => build_message(message),
+        Frame::Push(message) => build_push(message),
+        Frame::Chunk { meta, data } => build_chunk(meta, data),
+    }
+}
+
+/// 帧头长度：3 字节魔数加 4 字节长度
+const HEADER_LEN: usize = 7;
+/// 单帧数据的最大长度，超过时认为帧头已经损坏
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// 每次从连接中读取的字节数
+const READ_SIZE: usize = 8 * 1024;
+
+/// 解码出错的原因。除了 `Io` 和 `Truncated`，其余错误只丢弃出错的数据，连接可以继续使用
+#[derive(Debug)]
+pub enum FrameError {
+    /// 读写出错或超时，超时前已经收到的数据保留在缓冲区中
+    Io(io::Error),
+    /// 连接在一帧的中间被关闭
+    Truncated { expected: usize, received: usize },
+    /// 长度超过 `MAX_FRAME_SIZE`，跳过该帧头后重新查找魔数
+    TooLarge(usize),
+    /// 文本帧不是合法的 UTF-8，整帧丢弃
+    InvalidUtf8(Utf8Error),
+    /// 分片帧的描述长度与帧长度不符，或者描述不是合法的 UTF-8
+    BadChunk(&'static str),
+    /// 帧头不是魔数，跳过若干字节后重新对齐到下一个魔数
+    Resync(usize),
+}
+
+impl FrameError {
+    /// 数据有误但连接本身正常
+    pub fn is_protocol(&self) -> bool {
+        !matches!(self, FrameError::Io(_) | FrameError::Truncated { .. })
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Truncated { expected, received } => write!(
+                f,
+                "frame truncated: expected {} bytes, received {}",
+                expected, received
+            ),
+            FrameError::TooLarge(len) => {
+                write!(f, "frame too large: {} > {}", len, MAX_FRAME_SIZE)
+            }
+            FrameError::InvalidUtf8(e) => write!(f, "invalid utf-8 in frame: {}", e),
+            FrameError::BadChunk(reason) => write!(f, "bad chunk: {}", reason),
+            FrameError::Resync(skipped) => write!(f, "header not match, skipped {} bytes", skipped),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e,
+            FrameError::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
 }
 
-pub fn get_message_from_tcpstream_with_protocol(stream: &mut TcpStream) -> String {
-    let mut head = [0u8; 7];
-    loop {
-        if let Ok(head_size) = stream.read(&mut head) {
-            if head_size != 7 {
-                continue;
+/// 协议错误计数，按类型分别统计
+#[derive(Default)]
+pub struct ProtocolErrors {
+    pub truncated: AtomicU64,
+    pub too_large: AtomicU64,
+    pub invalid_utf8: AtomicU64,
+    pub bad_chunk: AtomicU64,
+    pub resync: AtomicU64,
+}
+
+impl ProtocolErrors {
+    pub fn record(&self, error: &FrameError) {
+        let counter = match error {
+            FrameError::Io(_) => return,
+            FrameError::Truncated { .. } => &self.truncated,
+            FrameError::TooLarge(_) => &self.too_large,
+            FrameError::InvalidUtf8(_) => &self.invalid_utf8,
+            FrameError::BadChunk(_) => &self.bad_chunk,
+            FrameError::Resync(_) => &self.resync,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> u64 {
+        [
+            &self.truncated,
+            &self.too_large,
+            &self.invalid_utf8,
+            &self.bad_chunk,
+            &self.resync,
+        ]
+        .iter()
+        .map(|counter| counter.load(Ordering::Relaxed))
+        .sum()
+    }
+}
+
+/// 带缓冲的帧读写。读取超时不会丢失已经收到的半帧数据，帧头损坏时扫描到下一个魔数继续解码
+pub struct FrameStream<S> {
+    inner: S,
+    buf: Vec<u8>,
+}
+
+impl<S> FrameStream<S> {
+    pub fn new(inner: S) -> Self {
+        FrameStream {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> FrameStream<S> {
+    /// 读取一帧：文本帧按 UTF-8 解码，分片帧拆出描述和原始数据
+    pub fn read_frame(&mut self) -> Result<Frame, FrameError> {
+        loop {
+            if let Some(result) = decode_frame(&mut self.buf) {
+                return result;
             }
-            if let Ok(data_size) = check_header(head) {
-                if data_size > 0 {
-                    let mut buffer = [0u8; 1024];
-                    let mut data: Vec<u8> = Vec::new();
-                    let mut remain_data = data_size;
-                    while remain_data > 0 {
-                        if let Ok(data_size) = stream.read(&mut buffer) {
-                            if data_size > 0 {
-                                remain_data -= data_size;
-                                data.extend((&buffer[..data_size]).to_vec())
-                            }
-                        }
-                    }
-                    if let Ok(msg) = String::from_utf8(data) {
-                        // println!("receive {} byte data, message={}", data_size, msg);
-                        return msg;
-                    }
-                }
+            self.fill()?;
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), FrameError> {
+        let start = self.buf.len();
+        self.buf.resize(start + READ_SIZE, 0);
+        let result = self.inner.read(&mut self.buf[start..]);
+        self.buf.truncate(start + *result.as_ref().unwrap_or(&0));
+        match result {
+            Ok(0) if self.buf.is_empty() => Err(FrameError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ))),
+            Ok(0) => {
+                let expected = match frame_len(&self.buf) {
+                    Some(len) => HEADER_LEN + len,
+                    None => HEADER_LEN,
+                };
+                let received = self.buf.len();
+                self.buf.clear();
+                Err(FrameError::Truncated { expected, received })
             }
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(FrameError::Io(e)),
         }
     }
 }
 
+impl<S: Write> FrameStream<S> {
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.inner.write_all(&build_frame(frame))?;
+        self.inner.flush()
+    }
+}
+
+/// 从缓冲区开头解码一帧，数据不完整时返回 `None`，已解码或丢弃的字节从缓冲区中移除
+pub fn decode_frame(buf: &mut Vec<u8>) -> Option<Result<Frame, FrameError>> {
+    let skipped = skip_to_magic(buf, 0);
+    if skipped > 0 {
+        return Some(Err(FrameError::Resync(skipped)));
+    }
+    let len = frame_len(buf)?;
+    if len > MAX_FRAME_SIZE {
+        skip_to_magic(buf, 1);
+        return Some(Err(FrameError::TooLarge(len)));
+    }
+    if buf.len() < HEADER_LEN + len {
+        return None;
+    }
+    let magic = [buf[0], buf[1], buf[2]];
+    let data = buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+    buf.drain(..HEADER_LEN + len);
+    Some(parse_frame(magic, data))
+}
+
+fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    Some(u32::from_be_bytes([buf[3], buf[4], buf[5], buf[6]]) as usize)
+}
 
+/// 丢弃 `from` 之前以及之后不是魔数开头的字节，缓冲区末尾可能是魔数前缀的部分保留，返回丢弃的字节数
+fn skip_to_magic(buf: &mut Vec<u8>, from: usize) -> usize {
+    let from = from.min(buf.len());
+    let pos = (from..buf.len())
+        .find(|&i| {
+            let rest = &buf[i..buf.len().min(i + 3)];
+            FRAME_MAGICS.iter().any(|magic| magic.starts_with(rest))
+        })
+        .unwrap_or(buf.len());
+    buf.drain(..pos);
+    pos
+}
+
+fn parse_frame(magic: [u8; 3], mut data: Vec<u8>) -> Result<Frame, FrameError> {
+    if magic != MAGIC_CHUNK {
+        let text = String::from_utf8(data).map_err(|e| FrameError::InvalidUtf8(e.utf8_error()))?;
+        return Ok(if magic == MAGIC_PUSH {
+            Frame::Push(text)
+        } else {
+            Frame::Text(text)
+        });
+    }
+    if data.len() < 2 {
+        return Err(FrameError::BadChunk("chunk too short"));
+    }
+    let meta_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    if data.len() < 2 + meta_len {
+        return Err(FrameError::BadChunk("chunk meta truncated"));
+    }
+    let chunk = data.split_off(2 + meta_len);
+    let meta = String::from_utf8(data.split_off(2))
+        .map_err(|_| FrameError::BadChunk("chunk meta is not valid utf-8"))?;
+    Ok(Frame::Chunk { meta, data: chunk })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// 按给定的长度分批返回数据，每批之间先返回一次超时，模拟网络上的拆包和读取超时
+    struct SlowReader {
+        data: Vec<u8>,
+        pos: usize,
+        sizes: Vec<usize>,
+        reads: usize,
+        timed_out: bool,
+    }
+
+    impl SlowReader {
+        fn new(data: Vec<u8>, sizes: Vec<usize>) -> Self {
+            SlowReader {
+                data,
+                pos: 0,
+                sizes,
+                reads: 0,
+                timed_out: false,
+            }
+        }
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.timed_out = !self.timed_out;
+            if !self.timed_out {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timeout"));
+            }
+            let size = self.sizes.get(self.reads).copied().unwrap_or(usize::MAX);
+            self.reads += 1;
+            let n = size.max(1).min(buf.len()).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    /// 读取下一个完整的帧，跳过超时和协议错误，连接关闭时返回 `None`
+    fn next_frame<R: Read>(stream: &mut FrameStream<R>, errors: &ProtocolErrors) -> Option<Frame> {
+        loop {
+            match stream.read_frame() {
+                Ok(frame) => return Some(frame),
+                Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(FrameError::Io(_)) => return None,
+                Err(e) => errors.record(&e),
+            }
+        }
+    }
+
+    fn frame_strategy() -> impl Strategy<Value = Frame> {
+        prop_oneof![
+            any::<String>().prop_map(Frame::Text),
+            any::<String>().prop_map(Frame::Push),
+            (
+                any::<String>(),
+                proptest::collection::vec(any::<u8>(), 0..512)
+            )
+                .prop_map(|(meta, data)| Frame::Chunk { meta, data }),
+        ]
+    }
+
+    fn decode_all(data: Vec<u8>) -> (Vec<Frame>, ProtocolErrors) {
+        let errors = ProtocolErrors::default();
+        let mut stream = FrameStream::new(io::Cursor::new(data));
+        let mut frames = Vec::new();
+        while let Some(frame) = next_frame(&mut stream, &errors) {
+            frames.push(frame);
+        }
+        (frames, errors)
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_with_split_reads(
+            frames in proptest::collection::vec(frame_strategy(), 0..8),
+            sizes in proptest::collection::vec(1usize..64, 0..64),
+        ) {
+            let data: Vec<u8> = frames.iter().flat_map(build_frame).collect();
+            let errors = ProtocolErrors::default();
+            let mut stream = FrameStream::new(SlowReader::new(data, sizes));
+            let mut decoded = Vec::new();
+            while let Some(frame) = next_frame(&mut stream, &errors) {
+                decoded.push(frame);
+            }
+            prop_assert_eq!(decoded, frames);
+            prop_assert_eq!(errors.total(), 0);
+        }
+
+        #[test]
+        fn resync_after_garbage(
+            garbage in proptest::collection::vec(any::<u8>().prop_filter("not magic", |b| *b != MAGIC[0]), 1..256),
+            frame in frame_strategy(),
+        ) {
+            let mut data = garbage;
+            data.extend(build_frame(&frame));
+            let (frames, errors) = decode_all(data);
+            prop_assert_eq!(frames, vec![frame]);
+            prop_assert!(errors.resync.load(Ordering::Relaxed) > 0);
+        }
+
+        #[test]
+        fn arbitrary_bytes_never_panic(data in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let mut buf = data.clone();
+            let mut rounds = 0;
+            while decode_frame(&mut buf).is_some() {
+                rounds += 1;
+                prop_assert!(rounds <= data.len() + 1);
+            }
+            prop_assert!(buf.len() < HEADER_LEN || matches!(frame_len(&buf), Some(len) if len <= MAX_FRAME_SIZE));
+        }
+
+        /// 长度字段损坏时无法判断帧的边界，其余位置损坏后后面的帧仍能正确解码
+        #[test]
+        fn corrupted_frame_does_not_lose_next(
+            before in frame_strategy(),
+            after in frame_strategy(),
+            index in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut data = build_frame(&before);
+            let mut i = index.index(data.len() - 4);
+            if i >= 3 {
+                i += 4;
+            }
+            data[i] = byte;
+            data.extend(build_frame(&after));
+            let (frames, _) = decode_all(data);
+            prop_assert_eq!(frames.last(), Some(&after));
+        }
+    }
+
+    #[test]
+    fn too_large_frame_is_skipped() {
+        let mut data = MAGIC.to_vec();
+        data.extend(((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        data.extend(b"garbage");
+        data.extend(build_message("ok"));
+        let (frames, errors) = decode_all(data);
+        assert_eq!(frames, vec![Frame::Text(String::from("ok"))]);
+        assert_eq!(errors.too_large.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(2u32.to_be_bytes());
+        buf.extend([0xff, 0xfe]);
+        buf.extend(build_message("ok"));
+        assert!(matches!(
+            decode_frame(&mut buf),
+            Some(Err(FrameError::InvalidUtf8(_)))
+        ));
+        assert_eq!(
+            decode_frame(&mut buf).unwrap().unwrap(),
+            Frame::Text(String::from("ok"))
+        );
+        assert!(decode_frame(&mut buf).is_none());
+    }
+
+    #[test]
+    fn build_chunk_layout() {
+        let data = build_chunk("{}", &[1, 2, 3]);
+        assert_eq!(&data[..3], &MAGIC_CHUNK);
+        assert_eq!(&data[3..7], &7u32.to_be_bytes());
+        assert_eq!(&data[7..9], &2u16.to_be_bytes());
+        assert_eq!(&data[9..11], b"{}");
+        assert_eq!(&data[11..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn chunk_with_empty_meta_and_data() {
+        let mut buf = build_chunk("", &[]);
+        assert_eq!(
+            decode_frame(&mut buf).unwrap().unwrap(),
+            Frame::Chunk {
+                meta: String::new(),
+                data: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn chunk_without_meta_length_is_reported() {
+        let mut buf = MAGIC_CHUNK.to_vec();
+        buf.extend(1u32.to_be_bytes());
+        buf.push(0);
+        assert!(matches!(
+            decode_frame(&mut buf),
+            Some(Err(FrameError::BadChunk(_)))
+        ));
+    }
+
+    #[test]
+    fn chunk_meta_invalid_utf8_is_reported() {
+        let mut buf = MAGIC_CHUNK.to_vec();
+        buf.extend(4u32.to_be_bytes());
+        buf.extend(2u16.to_be_bytes());
+        buf.extend([0xc3, 0x28]);
+        assert!(matches!(
+            decode_frame(&mut buf),
+            Some(Err(FrameError::BadChunk(_)))
+        ));
+    }
+
+    #[test]
+    fn bad_chunk_is_reported() {
+        let mut buf = MAGIC_CHUNK.to_vec();
+        buf.extend(3u32.to_be_bytes());
+        buf.extend(100u16.to_be_bytes());
+        buf.push(b'x');
+        assert!(matches!(
+            decode_frame(&mut buf),
+            Some(Err(FrameError::BadChunk(_)))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn truncated_frame_is_reported() {
+        let mut data = build_message("hello world");
+        data.truncate(10);
+        let mut stream = FrameStream::new(io::Cursor::new(data));
+        match stream.read_frame() {
+            Err(FrameError::Truncated { expected, received }) => {
+                assert_eq!(expected, HEADER_LEN + 11);
+                assert_eq!(received, 10);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(stream.read_frame(), Err(FrameError::Io(_))));
+    }
+}